@@ -1,9 +1,21 @@
-use crate::{ file::PostFile, http::CLIENT, target::Target, cli::ARGS };
+use crate::{
+    cli::{ ARGS, Extra, Layout },
+    file::PostFile,
+    limiter,
+    mirror,
+    pretty,
+    progress,
+    proxy::Lease,
+    retry::{ self, Cause },
+    target::{ Site, Target },
+    template,
+};
 use anyhow::{ bail, Result };
 use regex::Regex;
-use reqwest::StatusCode;
-use serde::{ de::DeserializeOwned, Deserialize };
-use std::sync::LazyLock;
+use reqwest::{ Response, StatusCode };
+use serde::{ de::{ self, DeserializeOwned }, Deserialize, Deserializer, Serialize };
+use serde_json::Value;
+use std::sync::{ Arc, LazyLock };
 use thiserror::Error;
 use tokio::time::{ Duration, sleep };
 
@@ -13,68 +25,223 @@ static RE_OUT_OF_BOUNDS: LazyLock<Regex> = LazyLock::new(||
     Regex::new(r#"\{"error":"Offset [0-9]+ is bigger than total count [0-9]+\."\}"#).unwrap()
 );
 
-async fn fetch<T: DeserializeOwned>(url: &str) -> Result<T, ApiError> {
+/// Sends a GET request to the API of a site (`path` is relative to its API base URL),
+/// through the proxy pool if there is one.
+///
+/// Connection, TLS and timeout errors count towards switching to the next domain of the site.
+pub async fn get(site: Site, path: &str) -> reqwest::Result<Response> {
+    let observed = mirror::current(site);
+
+    let api = site.api_url();
+
+    limiter::pace_api(&api).await;
+
+    let url = format!("{api}{path}");
+
+    let result = Lease::new().send(|client| client.get(url)).await;
+
+    match &result {
+        Ok(_) => mirror::succeed(site),
+        Err(err) if mirror::is_unreachable(err) => mirror::fail(site, observed),
+        Err(_) => {}
+    }
+
+    result
+}
+
+async fn fetch<T: DeserializeOwned>(site: Site, path: &str) -> Result<T, ApiError> {
     sleep(API_DELAY).await;
 
-    let res = CLIENT.get(url)
-        .send().await
-        .map_err(|err| ApiError::Connect(err.to_string()))?;
+    let res = get(site, path).await.map_err(|err| ApiError::Connect(err.to_string()))?;
 
     let status = res.status();
-    let text = res.text().await.expect("get text from response body");
+    let retry_after = retry::retry_after(res.headers());
+    let text = res.text().await.map_err(|err| ApiError::Connect(err.to_string()))?;
 
     if status == StatusCode::BAD_REQUEST && RE_OUT_OF_BOUNDS.is_match(&text) {
         return Ok(serde_json::from_str("[]").unwrap());
     }
 
     if status != StatusCode::OK {
-        return Err(ApiError::Status(status));
+        return Err(ApiError::Status(status, retry_after));
     }
 
     serde_json::from_str(&text).map_err(|err| ApiError::Parser(err.to_string()))
 }
 
 pub trait Post {
+    fn info(&self) -> &PostInfo;
+
     fn files(&mut self) -> Vec<PostFile>;
+
+    /// Post body as HTML, if the original JSON was kept
+    fn content(&self) -> Option<&str> {
+        None
+    }
+
+    /// Takes the post's original JSON, if it was kept
+    fn json(&mut self) -> Option<Value> {
+        None
+    }
+
+    /// The post's original JSON, if it was kept
+    fn raw(&self) -> Option<&Value> {
+        None
+    }
+}
+
+/// Post body as HTML; page listings only contain a shortened `substring`
+pub fn content(json: &Value) -> Option<&str> {
+    json.get("content")
+        .or_else(|| json.get("substring"))
+        .and_then(Value::as_str)
+}
+
+/// Keeps the original JSON of a post alongside its parsed form, if it is needed for
+/// `--write-metadata` or `--export`; otherwise only the content is kept for `--content-match`
+#[derive(Debug, Clone)]
+pub struct Json<T> {
+    post: T,
+    raw: Option<Value>,
+    content: Option<String>,
+}
+
+impl<T: DeserializeOwned> Json<T> {
+    fn from_value(raw: Value, keep: bool) -> Result<Self, serde_json::Error> {
+        let post = T::deserialize(&raw)?;
+
+        let content = if !keep && ARGS.content_match.is_some() {
+            content(&raw).map(ToString::to_string)
+        } else {
+            None
+        };
+
+        Ok(Self { post, raw: keep.then_some(raw), content })
+    }
+}
+
+impl<'de, T: DeserializeOwned> Deserialize<'de> for Json<T> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let raw = Value::deserialize(deserializer)?;
+        let keep = ARGS.write_metadata || ARGS.export.is_some();
+        Self::from_value(raw, keep).map_err(de::Error::custom)
+    }
+}
+
+impl<T: Post> Post for Json<T> {
+    fn info(&self) -> &PostInfo {
+        self.post.info()
+    }
+
+    fn files(&mut self) -> Vec<PostFile> {
+        self.post.files()
+    }
+
+    fn content(&self) -> Option<&str> {
+        self.raw.as_ref().map_or(self.content.as_deref(), content)
+    }
+
+    fn json(&mut self) -> Option<Value> {
+        self.raw.take()
+    }
+
+    fn raw(&self) -> Option<&Value> {
+        self.raw.as_ref()
+    }
+}
+
+/// Post metadata attached to every file found in the post
+#[derive(Debug, Clone, Default, Deserialize, Serialize, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct PostInfo {
+    pub id: String, // "1080444052",
+    pub title: Option<String>, // "silly lil dancing videos i did ☺️",
+    pub published: Option<String>, // "2024-05-21T17:19:18",
+    pub added: Option<String>, // "2024-05-26T03:04:55.971848",
+    #[serde(skip)]
+    pub section: Option<Extra>,
+}
+
+impl PostInfo {
+    /// Publication date, or the date the post was imported if it is unknown
+    pub fn date(&self) -> Option<&str> {
+        self.published.as_deref().or(self.added.as_deref())
+    }
+
+    /// Subdirectory of the target directory according to `--layout`
+    pub fn to_subdir(&self) -> Option<String> {
+        // DMs, fancards, etc. have their own subdirectory regardless of the layout
+        if let Some(section) = self.section {
+            return Some(section.to_string());
+        }
+
+        match ARGS.layout {
+            Layout::Flat => None,
+            Layout::Post => Some(template::sanitize(&self.id)),
+            Layout::PostTitle =>
+                Some(
+                    match self.title.as_deref().map(template::sanitize) {
+                        Some(title) if !title.is_empty() =>
+                            format!("{}_{title}", template::sanitize(&self.id)),
+                        _ => template::sanitize(&self.id),
+                    }
+                ),
+            Layout::YearMonth =>
+                Some(
+                    self
+                        .date()
+                        .and_then(|date| date.get(..7))
+                        .map_or_else(|| "unknown".to_string(), template::sanitize)
+                ),
+        }
+    }
+}
+
+/// Links files to their post, numbering them in order of appearance
+fn with_info(mut files: Vec<PostFile>, info: &PostInfo) -> Vec<PostFile> {
+    files.retain(PostFile::has_path);
+
+    let info = Arc::new(info.clone());
+
+    for (index, file) in files.iter_mut().enumerate() {
+        file.post = Some(info.clone());
+        file.index = index + 1;
+    }
+
+    files
 }
 
 #[derive(Debug, Clone, Error, PartialEq, Eq, PartialOrd, Ord)]
 pub enum ApiError {
     #[error("connection error")] Connect(String),
-    #[error("non-success status code")] Status(StatusCode),
+    #[error("non-success status code")] Status(StatusCode, Option<Duration>),
     #[error("post parsing failed")] Parser(String),
 }
 
 impl ApiError {
     pub async fn interpret(&self, retries: usize) -> Result<()> {
-        async fn wait_or_bail(retries: usize, duration: Duration, error: &str) -> Result<()> {
-            if retries < ARGS.max_retries {
-                sleep(duration).await;
-            } else {
-                bail!("{error}");
-            }
-
-            Ok(())
-        }
+        let (cause, retry_after, error) = match self {
+            ApiError::Connect(err) | ApiError::Parser(err) => (Cause::Error, None, err.clone()),
+            ApiError::Status(status, retry_after) =>
+                (
+                    Cause::from_status(*status).unwrap_or(Cause::Error),
+                    *retry_after,
+                    status.to_string(),
+                ),
+        };
 
-        match self {
-            ApiError::Connect(err) => wait_or_bail(retries, ARGS.retry_delay, err).await?,
-            ApiError::Status(status) =>
-                match status.as_u16() {
-                    403 | 429 | 502..=504 =>
-                        wait_or_bail(retries, ARGS.rate_limit_backoff, &status.to_string()).await?,
-                    _ => wait_or_bail(retries, ARGS.retry_delay, &status.to_string()).await?,
-                }
-            ApiError::Parser(err) => wait_or_bail(retries, ARGS.retry_delay, err).await?,
+        if retries >= ARGS.max_retries {
+            bail!("{error}");
         }
 
+        sleep(retry::delay(cause, retries, retry_after)).await;
+
         Ok(())
     }
 }
 
-#[derive(Debug, Clone, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+#[derive(Debug, Clone, Deserialize)]
 pub struct SinglePost {
-    post: SinglePostInner,
+    post: Json<SinglePostInner>,
     // "attachments": [
     //   {
     //     "server": "https://n4.coomer.su",
@@ -133,49 +300,208 @@ pub struct SinglePost {
     //     },
 }
 
-#[derive(Debug, Clone, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+#[derive(Debug, Clone, Deserialize)]
 struct SinglePostInner {
+    #[serde(flatten)]
+    info: PostInfo,
     file: Option<PostFile>,
     attachments: Vec<PostFile>,
 }
 
-impl Post for SinglePost {
+impl Post for SinglePostInner {
+    fn info(&self) -> &PostInfo {
+        &self.info
+    }
+
     fn files(&mut self) -> Vec<PostFile> {
         let mut files = Vec::new();
-        if let Some(file) = self.post.file.as_ref() {
+        if let Some(file) = self.file.as_ref() {
             files.push(file.clone());
         }
-        files.append(&mut self.post.attachments);
-        files.retain(PostFile::has_path);
-        files
+        files.append(&mut self.attachments);
+        with_info(files, &self.info)
     }
 }
 
-pub async fn page(target: &Target, user: &str, offset: usize) -> Result<Vec<PagePost>, ApiError> {
-    fetch(
-        &format!(
-            "https://{site}/api/v1/{service}/user/{user}/posts?o={offset}",
-            site = target.as_service().site(),
-            service = target.as_service()
-        )
-    ).await
+impl Post for SinglePost {
+    fn info(&self) -> &PostInfo {
+        self.post.info()
+    }
+
+    fn files(&mut self) -> Vec<PostFile> {
+        self.post.files()
+    }
+
+    fn content(&self) -> Option<&str> {
+        self.post.content()
+    }
+
+    fn json(&mut self) -> Option<Value> {
+        self.post.json()
+    }
 }
 
-#[derive(Debug, Clone, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+pub async fn page(
+    target: &Target,
+    user: &str,
+    offset: usize
+) -> Result<Vec<Json<PagePost>>, ApiError> {
+    let service = target.as_service();
+
+    fetch(service.to_site(), &format!("/{service}/user/{user}/posts?o={offset}")).await
+}
+
+/// Body of a single post, which unlike page listings is not shortened
+pub async fn post_content(
+    target: &Target,
+    user: &str,
+    post: &str
+) -> Result<Option<String>, ApiError> {
+    let service = target.as_service();
+
+    let json: Value = fetch(service.to_site(), &format!("/{service}/user/{user}/post/{post}")).await?;
+
+    Ok(
+        json
+            .get("post")
+            .and_then(|post| post.get("content"))
+            .and_then(Value::as_str)
+            .map(ToString::to_string)
+    )
+}
+
+#[derive(Debug, Clone, Deserialize)]
 pub struct PagePost {
+    #[serde(flatten)]
+    info: PostInfo,
     file: Option<PostFile>,
     attachments: Vec<PostFile>,
 }
 
 impl Post for PagePost {
+    fn info(&self) -> &PostInfo {
+        &self.info
+    }
+
     fn files(&mut self) -> Vec<PostFile> {
         let mut files = Vec::new();
         if let Some(file) = self.file.as_ref() {
             files.push(file.clone());
         }
         files.append(&mut self.attachments);
-        files.retain(PostFile::has_path);
-        files
+        with_info(files, &self.info)
+    }
+}
+
+pub async fn extra(
+    target: &Target,
+    user: &str,
+    section: Extra,
+    offset: usize
+) -> Result<Vec<Json<ExtraPost>>, ApiError> {
+    let service = target.as_service();
+
+    let mut path = format!("/{service}/user/{user}/{section}");
+
+    if section.is_paged() {
+        path.push_str(&format!("?o={offset}"));
+    }
+
+    // sections are saved as a whole, so their JSON is always kept
+    let raw: Vec<Value> = fetch(service.to_site(), &path).await?;
+
+    let mut posts = Vec::new();
+    let mut unidentified = 0;
+
+    for raw in raw {
+        let mut post = Json::<ExtraPost>
+            ::from_value(raw, true)
+            .map_err(|err| ApiError::Parser(err.to_string()))?;
+
+        if post.post.init(section) {
+            posts.push(post);
+        } else {
+            unidentified += 1;
+        }
+    }
+
+    // file names, sidecars and post directories of such posts would collide
+    if unidentified > 0 {
+        progress::suspend(||
+            eprintln!("{section}: skipping {} without an ID, hash or file", pretty::posts(unidentified))
+        );
+    }
+
+    Ok(posts)
+}
+
+/// DM, fancard, announcement or community post; these are identified by their hash, or
+/// the hash of their first file, if they do not have an ID
+#[derive(Debug, Clone, Deserialize)]
+pub struct ExtraPost {
+    id: Option<Value>, // 1234 / "1234"
+    hash: Option<String>, // "7d1c604743b8bf30c7c5a260ac487ae1dea4ef133cc767ed0c5989c981d56823",
+    title: Option<String>,
+    published: Option<String>,
+    added: Option<String>,
+    file: Option<PostFile>,
+    #[serde(default)]
+    attachments: Vec<PostFile>,
+    path: Option<String>, // fancards only
+    #[serde(skip)]
+    info: PostInfo,
+}
+
+impl ExtraPost {
+    /// Returns `false` if the post can't be identified
+    fn init(&mut self, section: Extra) -> bool {
+        // a fancard is a single image; fancards without a path have nothing to download
+        if section == Extra::Fancards && self.file.is_none() && let Some(path) = self.path.take() {
+            self.file = Some(PostFile { path: Some(path), name: None, post: None, index: 0 });
+        }
+
+        let id = match &self.id {
+            Some(Value::String(id)) if !id.is_empty() => Some(id.clone()),
+            Some(Value::Number(id)) => Some(id.to_string()),
+            _ => None,
+        };
+
+        let Some(id) = id
+            .or_else(|| self.hash.clone().filter(|hash| !hash.is_empty()))
+            .or_else(||
+                self.file
+                    .iter()
+                    .chain(&self.attachments)
+                    .filter(|file| file.path.is_some())
+                    .find_map(PostFile::to_hash)
+            ) else {
+            return false;
+        };
+
+        self.info = PostInfo {
+            id,
+            title: self.title.take(),
+            published: self.published.take(),
+            added: self.added.take(),
+            section: Some(section),
+        };
+
+        true
+    }
+}
+
+impl Post for ExtraPost {
+    fn info(&self) -> &PostInfo {
+        &self.info
+    }
+
+    fn files(&mut self) -> Vec<PostFile> {
+        let mut files = Vec::new();
+        if let Some(file) = self.file.take() {
+            files.push(file);
+        }
+        files.append(&mut self.attachments);
+        with_info(files, &self.info)
     }
 }
 
@@ -186,23 +512,51 @@ pub struct DiscordChannel {
 }
 
 pub async fn discord_server(server: &str) -> Result<Vec<DiscordChannel>, ApiError> {
-    fetch(&format!("https://kemono.cr/api/v1/discord/channel/lookup/{server}")).await
+    fetch(Site::Kemono, &format!("/discord/channel/lookup/{server}")).await
 }
 
-#[derive(Debug, Clone, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+#[derive(Debug, Clone, Deserialize)]
 pub struct DiscordPost {
+    #[serde(flatten)]
+    info: PostInfo,
     attachments: Vec<PostFile>,
 }
 
 impl Post for DiscordPost {
+    fn info(&self) -> &PostInfo {
+        &self.info
+    }
+
     fn files(&mut self) -> Vec<PostFile> {
-        self.attachments
-            .drain(..)
-            .filter(PostFile::has_path)
-            .collect()
+        with_info(self.attachments.drain(..).collect(), &self.info)
     }
 }
 
-pub async fn discord_page(channel: &str, offset: usize) -> Result<Vec<DiscordPost>, ApiError> {
-    fetch(&format!("https://kemono.cr/api/v1/discord/channel/{channel}?o={offset}")).await
+pub async fn discord_page(
+    channel: &str,
+    offset: usize
+) -> Result<Vec<Json<DiscordPost>>, ApiError> {
+    fetch(Site::Kemono, &format!("/discord/channel/{channel}?o={offset}")).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn init(raw: Value) -> Option<String> {
+        let mut post: ExtraPost = serde_json::from_value(raw).unwrap();
+        post.init(Extra::Announcements).then_some(post.info.id)
+    }
+
+    #[test]
+    fn identifies_section_posts() {
+        let hash = "7d1c604743b8bf30c7c5a260ac487ae1dea4ef133cc767ed0c5989c981d56823";
+        let path = format!("/7d/1c/{hash}.mp4");
+
+        assert_eq!(init(json!({ "id": 12, "hash": "h" })), Some("12".to_string()));
+        assert_eq!(init(json!({ "id": "", "hash": "h" })), Some("h".to_string()));
+        assert_eq!(init(json!({ "attachments": [{ "path": path }] })), Some(hash.to_string()));
+        assert_eq!(init(json!({ "hash": "", "attachments": [{ "name": "x.jpg" }] })), None);
+    }
 }
@@ -0,0 +1,202 @@
+use crate::cli::{ self, ARGS };
+use jiff::Zoned;
+use std::sync::{ LazyLock, Mutex };
+use tokio::time::{ Duration, Instant, sleep };
+
+static SCHEDULE: LazyLock<Option<Schedule>> = LazyLock::new(||
+    ARGS.limit_rate.as_deref().map(|arg| Schedule::parse(arg).expect("valid bandwidth schedule"))
+);
+
+static BUCKET: LazyLock<Mutex<Bucket>> = LazyLock::new(||
+    Mutex::new(Bucket { rate: 0, tokens: 0.0, updated: Instant::now(), checked: None })
+);
+
+/// Time of day (in minutes) with its own rate
+struct Window {
+    start: u32,
+    end: u32,
+    rate: u64,
+}
+
+impl Window {
+    fn contains(&self, minute: u32) -> bool {
+        if self.start <= self.end {
+            (self.start..self.end).contains(&minute)
+        } else {
+            // the window wraps around midnight
+            minute >= self.start || minute < self.end
+        }
+    }
+}
+
+/// Rates in bytes per second, `0` meaning unlimited
+struct Schedule {
+    default: u64,
+    windows: Vec<Window>,
+}
+
+impl Schedule {
+    /// Parses `RATE` and `HH:MM-HH:MM=RATE` entries, separated by commas
+    fn parse(arg: &str) -> Result<Self, String> {
+        let mut default = None;
+        let mut windows = Vec::new();
+
+        for entry in arg.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+            if let Some((window, rate)) = entry.split_once('=') {
+                let (start, end) = window
+                    .split_once('-')
+                    .ok_or_else(|| format!("invalid time window: {window:?} (HH:MM-HH:MM)"))?;
+
+                windows.push(Window {
+                    start: parse_time(start)?,
+                    end: parse_time(end)?,
+                    rate: cli::bytes_from_size(rate)?,
+                });
+            } else if default.replace(cli::bytes_from_size(entry)?).is_some() {
+                return Err(format!("more than one rate outside of time windows: {arg:?}"));
+            }
+        }
+
+        Ok(Self { default: default.unwrap_or_default(), windows })
+    }
+
+    /// Rate for a time of day (in minutes), the first matching window wins
+    fn rate_at(&self, minute: u32) -> u64 {
+        self.windows
+            .iter()
+            .find(|window| window.contains(minute))
+            .map_or(self.default, |window| window.rate)
+    }
+}
+
+fn parse_time(time: &str) -> Result<u32, String> {
+    let invalid = || format!("invalid time: {time:?} (HH:MM)");
+
+    let (hours, minutes) = time.trim().split_once(':').ok_or_else(invalid)?;
+    let hours: u32 = hours.parse().map_err(|_| invalid())?;
+    let minutes: u32 = minutes.parse().map_err(|_| invalid())?;
+
+    if hours > 24 || minutes > 59 || (hours == 24 && minutes > 0) {
+        return Err(invalid());
+    }
+
+    Ok(hours * 60 + minutes)
+}
+
+/// Validates `--limit-rate`
+pub fn parse(arg: &str) -> Result<String, String> {
+    Schedule::parse(arg).map(|_| arg.to_string())
+}
+
+/// Minute of the day in local time
+fn minute_of_day() -> u32 {
+    let now = Zoned::now();
+
+    u32::try_from(i32::from(now.hour()) * 60 + i32::from(now.minute())).unwrap_or_default()
+}
+
+/// Shared budget of all downloads, allowing bursts of up to one second's worth of bytes
+struct Bucket {
+    rate: u64,
+    tokens: f64,
+    updated: Instant,
+    /// When the rate of the schedule was last looked up, at most once per second
+    checked: Option<Instant>,
+}
+
+/// Waits until `bytes` fit into the bandwidth budget of `--limit-rate`.
+///
+/// Downloads may overdraw the budget, each one waiting until its share is covered,
+/// so that the combined throughput of all tasks stays at the limit.
+#[allow(clippy::cast_precision_loss)]
+pub async fn throttle(bytes: usize) {
+    let Some(schedule) = SCHEDULE.as_ref() else {
+        return;
+    };
+
+    let wait = {
+        let mut bucket = BUCKET.lock().expect("lock bandwidth budget");
+
+        let now = Instant::now();
+
+        let rate = if
+            bucket.checked.is_none_or(|checked| now.duration_since(checked) >= Duration::from_secs(1))
+        {
+            bucket.checked = Some(now);
+            schedule.rate_at(minute_of_day())
+        } else {
+            bucket.rate
+        };
+
+        if rate == 0 {
+            bucket.rate = rate;
+            return;
+        }
+
+        let rate_f = rate as f64;
+
+        if bucket.rate == rate {
+            bucket.tokens = (
+                bucket.tokens +
+                now.duration_since(bucket.updated).as_secs_f64() * rate_f
+            ).min(rate_f);
+        } else {
+            // the schedule switched to another window
+            bucket.rate = rate;
+            bucket.tokens = rate_f;
+        }
+
+        bucket.updated = now;
+
+        bucket.tokens -= bytes as f64;
+
+        (bucket.tokens < 0.0).then(|| Duration::from_secs_f64(-bucket.tokens / rate_f))
+    };
+
+    if let Some(wait) = wait {
+        sleep(wait).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_schedules() {
+        let schedule = Schedule::parse("5M, 01:00-07:00=0,22:30-01:00=500K").unwrap();
+
+        assert_eq!(schedule.default, 5 * 1024 * 1024);
+        assert_eq!(schedule.windows.len(), 2);
+        assert_eq!((schedule.windows[0].start, schedule.windows[0].end), (60, 420));
+        assert_eq!((schedule.windows[1].start, schedule.windows[1].end), (1350, 60));
+        assert_eq!(schedule.windows[1].rate, 500 * 1024);
+
+        // only windows, unlimited otherwise
+        assert_eq!(Schedule::parse("00:00-24:00=1K").unwrap().default, 0);
+    }
+
+    #[test]
+    fn rejects_invalid_schedules() {
+        assert!(Schedule::parse("5M,1M").is_err());
+        assert!(Schedule::parse("01:00=1M").is_err());
+        assert!(Schedule::parse("01:00-25:00=1M").is_err());
+        assert!(Schedule::parse("01:60-02:00=1M").is_err());
+        assert!(Schedule::parse("24:01-02:00=1M").is_err());
+        assert!(Schedule::parse("1:00-2:00=fast").is_err());
+    }
+
+    #[test]
+    fn picks_the_first_matching_window() {
+        let schedule = Schedule::parse("5M,01:00-07:00=0,22:30-01:00=500K,06:00-08:00=1M").unwrap();
+
+        assert_eq!(schedule.rate_at(0), 500 * 1024);
+        assert_eq!(schedule.rate_at(59), 500 * 1024);
+        assert_eq!(schedule.rate_at(60), 0);
+        assert_eq!(schedule.rate_at(419), 0);
+        assert_eq!(schedule.rate_at(420), 1024 * 1024);
+        assert_eq!(schedule.rate_at(480), 5 * 1024 * 1024);
+        assert_eq!(schedule.rate_at(1349), 5 * 1024 * 1024);
+        assert_eq!(schedule.rate_at(1350), 500 * 1024);
+    }
+}
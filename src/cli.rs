@@ -1,30 +1,161 @@
+use crate::{ bandwidth, config, filter, target::Site, template };
 use anyhow::Result;
-use clap::{ Parser, arg };
+use clap::{
+    Args as ClapArgs,
+    CommandFactory,
+    FromArgMatches,
+    Parser,
+    Subcommand,
+    ValueEnum,
+    parser::ValueSource,
+};
 use itertools::Itertools;
 use pretty_duration::pretty_duration;
-use serde::Deserialize;
-use std::{ fmt, num, sync::LazyLock, time::Duration };
+use serde::{ Deserialize, Serialize };
+use std::{ env, fmt, num, process::exit, sync::LazyLock, time::Duration };
+use strum_macros::Display;
 
-pub static ARGS: LazyLock<Args> = LazyLock::new(Args::parse);
+pub static ARGS: LazyLock<Args> = LazyLock::new(Args::load);
 
 #[derive(Deserialize, Parser)]
-#[clap(about, version, arg_required_else_help = true)]
+#[clap(about, version)]
 pub struct Args {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
     #[arg(help = "Creator page or post / Discord server or channel")]
     pub urls: Vec<String>,
 
+    #[arg(long, help = "File with target URLs, one per line (- for stdin)")]
+    pub input_file: Option<String>,
+
+    #[arg(long, value_enum, help = "Download favorited creators and posts of your account")]
+    pub favorites: Option<Site>,
+
+    #[arg(long, help = "Download creators and posts from exported favorites (JSON)")]
+    pub favorites_file: Option<String>,
+
+    #[arg(long, help = "Session cookie of your account (sent to coomer and kemono)")]
+    pub session: Option<String>,
+
+    #[arg(long, help = "Cookies file (Netscape format) for coomer and kemono")]
+    pub cookies: Option<String>,
+
+    #[arg(
+        long,
+        help = "Config file, flags enabled there are turned off with --no-<flag> [default: $XDG_CONFIG_HOME/kumono/config.toml]"
+    )]
+    pub config: Option<String>,
+
+    #[arg(long, help = "Named profile from the config file")]
+    pub profile: Option<String>,
+
     #[arg(short, long, help = "Proxy URL (scheme://host:port[/path])")]
     pub proxy: Option<String>,
 
+    #[arg(
+        long,
+        conflicts_with = "proxy",
+        help = "File with one proxy URL per line, used in turns by API requests and downloads"
+    )]
+    pub proxy_file: Option<String>,
+
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = ProxyStrategy::RoundRobin,
+        help = "How requests are assigned to proxies from --proxy-file"
+    )]
+    pub proxy_strategy: ProxyStrategy,
+
+    #[arg(
+        long,
+        value_delimiter = ',',
+        default_value = "kemono.cr,kemono.su",
+        help = "Kemono domains in order of preference, optionally with a scheme (comma separated)"
+    )]
+    pub kemono_host: Vec<String>,
+
+    #[arg(
+        long,
+        value_delimiter = ',',
+        default_value = "coomer.st,coomer.su",
+        help = "Coomer domains in order of preference, optionally with a scheme (comma separated)"
+    )]
+    pub coomer_host: Vec<String>,
+
+    #[arg(long, help = "API base URL of both sites [default: <site-host>/api/v1]")]
+    pub api_base: Option<String>,
+
+    #[arg(long, help = "Kemono API base URL [default: <api-base>]")]
+    pub kemono_api: Option<String>,
+
+    #[arg(long, help = "Coomer API base URL [default: <api-base>]")]
+    pub coomer_api: Option<String>,
+
     #[arg(short, long, default_value_t = 256, help = "Simultaneous downloads (1-512)")]
     threads: usize,
 
+    #[arg(
+        long,
+        default_value_t = 1,
+        help = "Targets processed at once, indexing the next ones while downloading (1-16)"
+    )]
+    parallel_targets: usize,
+
+    #[arg(
+        long,
+        default_value_t = 4,
+        help = "API pages of a creator retrieved at once, once its post count is known (1-16)"
+    )]
+    page_concurrency: usize,
+
+    #[arg(
+        long,
+        value_parser = bandwidth::parse,
+        help = "Maximum download speed (e.g. 5M), optionally per time of day (e.g. 5M,01:00-07:00=0)"
+    )]
+    pub limit_rate: Option<String>,
+
+    #[arg(
+        long,
+        value_name = "REQ/S",
+        value_parser = rate_from_str,
+        help = "Maximum requests per second per host"
+    )]
+    pub rate_limit: Option<f64>,
+
     #[arg(short, long, default_value = "kumono", help = "Base directory for downloads")]
     pub output_path: String,
 
+    #[arg(
+        short,
+        long,
+        value_parser = template::parse,
+        help = "File name template, e.g. {published}_{post_id}_{index}_{name}.{ext}"
+    )]
+    pub filename: Option<String>,
+
+    #[arg(long, value_enum, default_value_t = Layout::Flat, help = "Directory layout per target")]
+    pub layout: Layout,
+
     #[arg(short, long, help = "List of available file extensions (per target)")]
     pub list_extensions: bool,
 
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = OutputFormat::Text,
+        help = "Output format; json prints one event per line to stdout"
+    )]
+    pub output_format: OutputFormat,
+
+    #[arg(long, help = "List files that would be downloaded, without writing anything")]
+    pub dry_run: bool,
+
+    #[arg(long, requires = "dry_run", help = "Include remote file sizes in the dry run")]
+    pub sizes: bool,
+
     #[arg(
         short,
         long,
@@ -43,26 +174,125 @@ pub struct Args {
     )]
     exclude: Option<Vec<String>>,
 
+    #[arg(
+        long,
+        value_parser = filter::parse_date,
+        help = "Only posts published on/after this date"
+    )]
+    pub after: Option<String>,
+
+    #[arg(
+        long,
+        value_parser = filter::parse_date,
+        help = "Only posts published before this date"
+    )]
+    pub before: Option<String>,
+
+    #[arg(long, help = "Only posts published since the newest post of the previous run")]
+    pub since_last_run: bool,
+
+    #[arg(
+        long,
+        value_parser = filter::parse_regex,
+        help = "Only posts with a matching title (regex)"
+    )]
+    pub title_match: Option<String>,
+
+    #[arg(
+        long,
+        value_parser = filter::parse_regex,
+        help = "Skip posts with a matching title (regex)"
+    )]
+    pub title_exclude: Option<String>,
+
+    #[arg(
+        long,
+        value_parser = filter::parse_regex,
+        help = "Only posts with matching content (regex)"
+    )]
+    pub content_match: Option<String>,
+
+    #[arg(
+        long,
+        value_parser = bytes_from_size,
+        help = "Skip files smaller than this (e.g. 100K)"
+    )]
+    pub min_size: Option<u64>,
+
+    #[arg(
+        long,
+        value_parser = bytes_from_size,
+        help = "Skip files larger than this (e.g. 2G)"
+    )]
+    pub max_size: Option<u64>,
+
+    #[arg(long, help = "Save post metadata as post_<id>.json next to its files")]
+    pub write_metadata: bool,
+
+    #[arg(long, value_enum, help = "Export posts as browsable pages, with an index per target")]
+    pub export: Option<ExportFormat>,
+
+    #[arg(
+        long,
+        value_enum,
+        value_delimiter = ',',
+        help = "Also download these sections of whole creators (comma separated)"
+    )]
+    pub extras: Vec<Extra>,
+
     #[arg(short, long, help = "Log hashes, skip moved/deleted file download")]
     pub download_archive: bool,
 
-    #[arg(short, long, default_value_t = 5)]
+    #[arg(
+        short,
+        long,
+        default_value_t = 5,
+        help = "Retries per API page, file size check or download"
+    )]
     pub max_retries: usize,
 
-    #[arg(short, long, value_parser = duration_from_secs, default_value = "1")]
+    #[arg(
+        short,
+        long,
+        value_parser = duration_from_secs,
+        default_value = "1",
+        help = "Initial delay after errors, doubled with every retry"
+    )]
     pub retry_delay: Duration,
 
     #[arg(long, value_parser = duration_from_secs, default_value = "1")]
     pub connect_timeout: Duration,
 
-    // TODO retry on timeout, lower timeout to 60~120 seconds
-    #[arg(long, value_parser = duration_from_secs, default_value = "180")]
+    #[arg(
+        long,
+        value_parser = duration_from_secs,
+        default_value = "120",
+        help = "Fail a request when no data arrives for this long"
+    )]
     pub read_timeout: Duration,
 
-    #[arg(long, value_parser = duration_from_secs, default_value = "15")]
+    #[arg(
+        long,
+        value_parser = duration_from_secs,
+        default_value = "30",
+        help = "Resume a download when no data arrives for this long"
+    )]
+    pub stall_timeout: Duration,
+
+    #[arg(
+        long,
+        value_parser = duration_from_secs,
+        default_value = "15",
+        help = "Initial delay after 403/429, unless the server sends Retry-After"
+    )]
     pub rate_limit_backoff: Duration,
 
-    #[arg(long, value_parser = duration_from_secs, default_value = "5")]
+    #[arg(
+        long,
+        value_parser = duration_from_secs,
+        default_value = "5",
+        help = "Initial delay after 5xx, unless the server sends Retry-After"
+    )]
     pub server_error_delay: Duration,
 
     #[arg(short, long, help = "Print configuration")]
@@ -72,15 +302,219 @@ pub struct Args {
     // pub verbose: bool,
 }
 
+#[derive(Debug, Clone, Deserialize, Subcommand)]
+pub enum Command {
+    /// Download creators from the watchlist that were updated since the last sync
+    Sync(SyncArgs),
+    /// Continue the last run that was interrupted or had failed downloads
+    Resume,
+}
+
+#[derive(Debug, Clone, Deserialize, ClapArgs)]
+pub struct SyncArgs {
+    #[arg(long, num_args = 1.., help = "Add URLs to the watchlist")]
+    pub add: Vec<String>,
+
+    #[arg(long, num_args = 1.., help = "Remove URLs from the watchlist")]
+    pub remove: Vec<String>,
+
+    #[arg(long, help = "Print the watchlist")]
+    pub list: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum Layout {
+    /// All files in one directory
+    Flat,
+    /// One subdirectory per post, named after the post ID
+    Post,
+    /// One subdirectory per post, named after the post ID and title
+    PostTitle,
+    /// One subdirectory per month of publication, or of import if unknown (YYYY-MM)
+    YearMonth,
+}
+
+/// Creator sections besides regular posts, each saved into its own subdirectory
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Hash,
+    Deserialize,
+    Serialize,
+    ValueEnum,
+    Display
+)]
+#[serde(rename_all = "kebab-case")]
+#[strum(serialize_all = "lowercase")]
+pub enum Extra {
+    /// Direct messages
+    Dms,
+    /// Fan cards
+    Fancards,
+    /// Announcements
+    Announcements,
+    /// Community posts
+    Community,
+}
+
+impl Extra {
+    /// Sections that are split into pages like regular posts
+    pub fn is_paged(self) -> bool {
+        matches!(self, Extra::Dms | Extra::Community)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum ProxyStrategy {
+    /// Each download uses the next proxy
+    RoundRobin,
+    /// Each download uses the proxy with the fewest active downloads
+    LeastLoaded,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum OutputFormat {
+    /// Progress bars and messages for humans
+    Text,
+    /// JSON Lines events for scripts
+    Json,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum ExportFormat {
+    Html,
+    Markdown,
+}
+
+impl ExportFormat {
+    pub fn extension(self) -> &'static str {
+        match self {
+            ExportFormat::Html => "html",
+            ExportFormat::Markdown => "md",
+        }
+    }
+}
+
+/// Parses a byte size with an optional binary unit suffix (K, M, G, T)
+pub fn bytes_from_size(arg: &str) -> Result<u64, String> {
+    let arg = arg.trim();
+
+    let (number, unit) = match arg.find(|c: char| c.is_ascii_alphabetic()) {
+        Some(i) => arg.split_at(i),
+        None => (arg, ""),
+    };
+
+    let multiplier: u64 = match unit.to_ascii_uppercase().trim_end_matches(['B', 'I']) {
+        "" => 1,
+        "K" => 1 << 10,
+        "M" => 1 << 20,
+        "G" => 1 << 30,
+        "T" => 1 << 40,
+        _ => {
+            return Err(format!("unknown unit: {unit}"));
+        }
+    };
+
+    let number: f64 = number.trim().parse().map_err(|err: num::ParseFloatError| err.to_string())?;
+
+    if number.is_sign_negative() || !number.is_finite() {
+        return Err("size must be a positive number".to_string());
+    }
+
+    #[allow(clippy::cast_precision_loss)]
+    let bytes = number * (multiplier as f64);
+
+    // the cast would silently saturate at u64::MAX
+    #[allow(clippy::cast_precision_loss)]
+    if bytes >= u64::MAX as f64 {
+        return Err("size is too large".to_string());
+    }
+
+    #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+    Ok(bytes as u64)
+}
+
+fn rate_from_str(arg: &str) -> Result<f64, String> {
+    match arg.parse::<f64>() {
+        Ok(rate) if rate > 0.0 && rate.is_finite() => Ok(rate),
+        _ => Err(format!("invalid rate: {arg:?} (must be a positive number)")),
+    }
+}
+
 fn duration_from_secs(arg: &str) -> Result<Duration, num::ParseIntError> {
     Ok(Duration::from_secs(arg.parse::<u64>()?.clamp(1, u64::MAX)))
 }
 
 impl Args {
+    /// Parses the command line on top of the options from the config file
+    fn load() -> Self {
+        let mut cli: Vec<_> = env::args_os().collect();
+
+        let negated = config::take_negations(&mut cli);
+
+        let matches = Self::command().get_matches_from(&cli);
+
+        let args = Self::from_arg_matches(&matches).unwrap_or_else(|err| err.exit());
+
+        let on_cli = |id: &str|
+            matches.value_source(id) == Some(ValueSource::CommandLine) ||
+            negated.iter().any(|negated| negated == id);
+
+        let mut config_args = config
+            ::to_args(args.config.as_deref(), args.profile.as_deref(), on_cli)
+            .unwrap_or_else(|err| {
+                eprintln!("{err:#}");
+                exit(1);
+            });
+
+        let args = if config_args.is_empty() {
+            args
+        } else {
+            let mut argv = cli[..1].to_vec();
+            argv.append(&mut config_args);
+            argv.extend_from_slice(&cli[1..]);
+
+            Self::parse_from(argv)
+        };
+
+        // targets may come from the config file, so help is only shown when there are none
+        if cli.len() == 1 && !args.has_targets() {
+            eprintln!("{}", Self::command().render_help());
+            exit(2);
+        }
+
+        args
+    }
+
+    fn has_targets(&self) -> bool {
+        self.command.is_some() ||
+            !self.urls.is_empty() ||
+            self.input_file.is_some() ||
+            self.favorites.is_some() ||
+            self.favorites_file.is_some()
+    }
+
     pub fn threads(&self) -> usize {
         self.threads.clamp(1, 512)
     }
 
+    pub fn parallel_targets(&self) -> usize {
+        self.parallel_targets.clamp(1, 16)
+    }
+
+    pub fn page_concurrency(&self) -> usize {
+        self.page_concurrency.clamp(1, 16)
+    }
+
     pub fn included(&self) -> Option<Vec<String>> {
         Self::process_exts(self.include.as_ref()?)
     }
@@ -112,13 +546,46 @@ impl fmt::Display for Args {
 
         write!(
             f,
-            "Threads: {} / Proxy: {} / Timeout: (Connect: {} / Read: {}) / Backoff: (Rate Limit: {} / Server Error: {})",
+            "Threads: {} / Parallel Targets: {} / Page Concurrency: {} / Proxy: {} / Rate Limit: {} / Bandwidth: {} / Timeout: (Connect: {} / Read: {} / Stall: {}) / Backoff: (Rate Limit: {} / Server Error: {})",
             self.threads(),
+            self.parallel_targets(),
+            self.page_concurrency(),
             self.proxy.as_ref().map_or("None", |p| p),
+            self.rate_limit.map_or_else(|| "None".to_string(), |r| format!("{r} req/s")),
+            self.limit_rate.as_deref().unwrap_or("None"),
             pd(&self.connect_timeout),
             pd(&self.read_timeout),
+            pd(&self.stall_timeout),
             pd(&self.rate_limit_backoff),
             pd(&self.server_error_delay)
         )
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_sizes() {
+        let cases = [
+            ("0", Ok(0)),
+            ("512", Ok(512)),
+            ("1.5K", Ok(1536)),
+            ("2kb", Ok(2048)),
+            ("10 MiB", Ok(10 << 20)),
+            ("1G", Ok(1 << 30)),
+            ("4T", Ok(4 << 40)),
+            ("1P", Err("unknown unit: P".to_string())),
+            ("-1M", Err("size must be a positive number".to_string())),
+            ("16000000T", Ok(16_000_000 << 40)),
+            ("17000000T", Err("size is too large".to_string())),
+        ];
+
+        for (arg, expected) in cases {
+            assert_eq!(bytes_from_size(arg), expected, "{arg}");
+        }
+
+        assert!(bytes_from_size("K").is_err());
+    }
+}
@@ -0,0 +1,216 @@
+use crate::cli::Args;
+use anyhow::{ Context, Result, bail };
+use clap::{ ArgAction, CommandFactory };
+use std::{ env, ffi::OsString, fs, io::ErrorKind, path::PathBuf };
+use toml::{ Table, Value };
+
+/// Options that only make sense on the command line
+const CLI_ONLY: [&str; 3] = ["urls", "config", "profile"];
+
+/// `$XDG_CONFIG_HOME/kumono/config.toml`, falling back to `~/.config`
+fn default_path() -> Option<PathBuf> {
+    let config_home = env::var_os("XDG_CONFIG_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))?;
+
+    Some(config_home.join("kumono").join("config.toml"))
+}
+
+/// Reads the config file and turns the top-level options, followed by those of the
+/// selected profile, into command line arguments.
+///
+/// Options given on the command line (`on_cli`), or conflicting with one that was,
+/// are left out, so that the command line takes precedence.
+pub fn to_args(
+    path: Option<&str>,
+    profile: Option<&str>,
+    on_cli: impl Fn(&str) -> bool
+) -> Result<Vec<OsString>> {
+    let (path, required) = match path {
+        Some(path) => (PathBuf::from(path), true),
+        None =>
+            match default_path() {
+                Some(path) => (path, false),
+                None if profile.is_some() => bail!("no config file found for profile {profile:?}"),
+                None => {
+                    return Ok(Vec::new());
+                }
+            }
+    };
+
+    let text = match fs::read_to_string(&path) {
+        Ok(text) => text,
+        Err(err) if err.kind() == ErrorKind::NotFound && !required && profile.is_none() => {
+            return Ok(Vec::new());
+        }
+        Err(err) => {
+            return Err(err).with_context(|| format!("read config file: {}", path.display()));
+        }
+    };
+
+    let mut config: Table = toml
+        ::from_str(&text)
+        .with_context(|| format!("parse config file: {}", path.display()))?;
+
+    let profiles = config.remove("profiles");
+
+    let mut options = normalize(config);
+
+    if let Some(name) = profile {
+        let Some(Value::Table(profile_options)) = profiles
+            .as_ref()
+            .and_then(Value::as_table)
+            .and_then(|profiles| profiles.get(name)) else {
+            bail!("profile {name:?} not found in config file: {}", path.display());
+        };
+
+        // profile options replace top-level options, including conflicting ones
+        for (id, value) in normalize(profile_options.clone()) {
+            for conflict in conflicts(&id) {
+                options.remove(&conflict);
+            }
+            options.insert(id, value);
+        }
+    }
+
+    options_to_args(&options, on_cli)
+}
+
+/// Takes `--no-<flag>` out of the command line, returning the IDs of those flags so
+/// that they are left out of the config file, which is how a flag enabled there is
+/// turned off for a single run.
+pub fn take_negations(cli: &mut Vec<OsString>) -> Vec<String> {
+    let command = Args::command();
+
+    let mut negated = Vec::new();
+
+    let mut i = 1;
+
+    while i < cli.len() {
+        let Some(arg) = cli[i].to_str() else {
+            i += 1;
+            continue;
+        };
+
+        // everything after `--` is a URL
+        if arg == "--" {
+            break;
+        }
+
+        let flag = arg
+            .strip_prefix("--no-")
+            .and_then(|long| command.get_arguments().find(|arg| arg.get_long() == Some(long)))
+            .filter(|arg| matches!(arg.get_action(), ArgAction::SetTrue));
+
+        if let Some(flag) = flag {
+            negated.push(flag.get_id().to_string());
+            cli.remove(i);
+        } else {
+            i += 1;
+        }
+    }
+
+    negated
+}
+
+/// Uses argument IDs (`snake_case`) as keys, so both spellings of an option are accepted
+fn normalize(options: Table) -> Table {
+    options
+        .into_iter()
+        .map(|(key, value)| (key.replace('-', "_"), value))
+        .collect()
+}
+
+fn conflicts(id: &str) -> Vec<String> {
+    let command = Args::command();
+
+    command
+        .get_arguments()
+        .find(|arg| arg.get_id() == id)
+        .map(|arg| {
+            command
+                .get_arg_conflicts_with(arg)
+                .iter()
+                .map(|conflict| conflict.get_id().to_string())
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+fn options_to_args(options: &Table, on_cli: impl Fn(&str) -> bool) -> Result<Vec<OsString>> {
+    let command = Args::command();
+
+    let mut args = Vec::new();
+
+    for (id, value) in options {
+        let Some(arg) = command
+            .get_arguments()
+            .find(|arg| arg.get_id() == id.as_str())
+            .filter(|arg| arg.get_long().is_some() && !CLI_ONLY.contains(&id.as_str())) else {
+            bail!("config: unknown option {id:?}");
+        };
+
+        if on_cli(id) || conflicts(id).iter().any(|conflict| on_cli(conflict)) {
+            continue;
+        }
+
+        let long = arg.get_long().expect("long option");
+
+        let value = match value {
+            Value::Boolean(true) => None,
+            Value::Boolean(false) => {
+                continue;
+            }
+            Value::String(s) => Some(s.clone()),
+            Value::Integer(n) => Some(n.to_string()),
+            Value::Float(n) => Some(n.to_string()),
+            Value::Array(values) =>
+                Some(
+                    values
+                        .iter()
+                        .map(|v| v.as_str().map_or_else(|| v.to_string(), ToString::to_string))
+                        .collect::<Vec<_>>()
+                        .join(",")
+                ),
+            _ => bail!("config: unsupported value for option {id:?}"),
+        };
+
+        args.push(
+            OsString::from(match value {
+                Some(value) => format!("--{long}={value}"),
+                None => format!("--{long}"),
+            })
+        );
+    }
+
+    Ok(args)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<OsString> {
+        args.iter().map(OsString::from).collect()
+    }
+
+    #[test]
+    fn takes_negated_flags_off_the_command_line() {
+        let mut cli = args(&["kumono", "--no-write-metadata", "--no-threads", "url", "--", "--no-dry-run"]);
+
+        assert_eq!(take_negations(&mut cli), ["write_metadata"]);
+        assert_eq!(cli, args(&["kumono", "--no-threads", "url", "--", "--no-dry-run"]));
+    }
+
+    #[test]
+    fn leaves_out_options_given_on_the_command_line() {
+        let options: Table = toml
+            ::from_str("threads = 8\nwrite-metadata = true\ndry-run = true\nexclude = [\"gif\", \"mp4\"]")
+            .unwrap();
+
+        let args = options_to_args(&normalize(options), |id| id == "write_metadata").unwrap();
+
+        assert_eq!(args, self::args(&["--dry-run", "--exclude=gif,mp4", "--threads=8"]));
+    }
+}
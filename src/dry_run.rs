@@ -0,0 +1,115 @@
+use crate::{
+    cli::ARGS,
+    file::PostFile,
+    output::{ self, Event, FileRef },
+    pretty,
+    progress::{ self, DownloadAction },
+    proxy::Lease,
+    target::Target,
+};
+use anyhow::Result;
+use futures::{ StreamExt, stream };
+use indicatif::HumanBytes;
+use std::collections::HashSet;
+use tokio::sync::mpsc;
+
+/// Prints URL and destination (and remote size) of every file that would be downloaded
+pub async fn list(target: &Target, files: HashSet<PostFile>) -> Result<()> {
+    let mut files: Vec<PostFile> = files.into_iter().collect();
+    files.sort_by_cached_key(|file| file.to_pathbuf(target));
+
+    let total = files.len();
+
+    let mut queued = Vec::new();
+
+    for file in files {
+        if !file.exists(target).await? {
+            queued.push(file);
+        }
+    }
+
+    if queued.len() != total {
+        crate::files_left_msg("existing files", total, queued.len());
+    }
+
+    let with_sizes = ARGS.sizes || ARGS.min_size.is_some() || ARGS.max_size.is_some();
+
+    let sizes: Vec<Option<u64>> = if with_sizes {
+        stream
+            ::iter(&queued)
+            .map(|file| remote_size(file, target))
+            .buffered(ARGS.threads())
+            .collect().await
+    } else {
+        vec![None; queued.len()]
+    };
+
+    let (mut count, mut bytes, mut unknown) = (0, 0, 0);
+
+    for (file, size) in queued.iter().zip(sizes) {
+        if
+            let Some(size) = size &&
+            (ARGS.min_size.is_some_and(|min| size < min) ||
+                ARGS.max_size.is_some_and(|max| size > max))
+        {
+            continue;
+        }
+
+        count += 1;
+
+        if output::is_json() {
+            output::emit(&(Event::Queued { file: FileRef::new(file, target), size }));
+        } else {
+            let (url, path) = (file.to_url(target), file.to_pathbuf(target));
+
+            if with_sizes {
+                println!(
+                    "{url}\t{}\t{}",
+                    path.display(),
+                    size.map_or_else(|| "?".to_string(), |size| size.to_string())
+                );
+            } else {
+                println!("{url}\t{}", path.display());
+            }
+        }
+
+        if let Some(size) = size {
+            bytes += size;
+        } else if with_sizes {
+            unknown += 1;
+        }
+    }
+
+    progress::suspend(|| {
+        if with_sizes {
+            eprintln!(
+                "dry run: {} would be downloaded, {} in total{}",
+                pretty::files(count),
+                HumanBytes(bytes),
+                if unknown > 0 {
+                    format!(" ({} of unknown size)", pretty::files(unknown))
+                } else {
+                    String::new()
+                }
+            );
+        } else {
+            eprintln!("dry run: {} would be downloaded", pretty::files(count));
+        }
+    });
+
+    Ok(())
+}
+
+async fn remote_size(file: &PostFile, target: &Target) -> Option<u64> {
+    // remote size checks report waits for the progress bar, which is not used here
+    let (mut msg_tx, mut msg_rx) = mpsc::channel::<DownloadAction>(1);
+    tokio::spawn(async move { while msg_rx.recv().await.is_some() {} });
+
+    match file.remote_size(target, &mut Lease::new(), &mut msg_tx, &mut 0).await {
+        Ok(size) => Some(size),
+        Err(err) => {
+            eprintln!("{err}");
+            None
+        }
+    }
+}
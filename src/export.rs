@@ -0,0 +1,305 @@
+use crate::{
+    api,
+    cli::ExportFormat,
+    file::PostFile,
+    metadata::{ self, PostRecord },
+    pretty,
+    progress,
+    target::Target,
+};
+use anyhow::{ Context, Result };
+use serde_json::Value;
+use std::{ fmt::Write, mem, path::{ Component, Path } };
+use tokio::fs;
+
+/// Writes one page per post and an index page for the target, returns the number of posts.
+///
+/// Called once the downloads of the target finished, so that pages only link files on disk.
+pub async fn write(
+    target: &Target,
+    mut records: Vec<PostRecord>,
+    format: ExportFormat
+) -> Result<usize> {
+    let index_path = target.to_pathbuf(Some(&format!("index.{}", format.extension())));
+
+    for record in &mut records {
+        record.files = on_disk(target, mem::take(&mut record.files)).await?;
+    }
+
+    retrieve_contents(target, &mut records).await;
+
+    let mut records: Vec<&PostRecord> = records.iter().collect();
+    records.sort_by(|a, b| b.info.published.cmp(&a.info.published));
+
+    let mut entries = Vec::new();
+
+    for record in &records {
+        let page_path = metadata::to_post_pathbuf(target, &record.info, format.extension());
+
+        let page = match format {
+            ExportFormat::Html => post_html(target, record, &page_path, &index_path),
+            ExportFormat::Markdown => post_markdown(target, record, &page_path, &index_path),
+        };
+
+        write_page(&page_path, &page).await?;
+
+        entries.push((*record, link(&index_path, &page_path)));
+    }
+
+    let index = match format {
+        ExportFormat::Html => index_html(target, &entries),
+        ExportFormat::Markdown => index_markdown(target, &entries),
+    };
+
+    write_page(&index_path, &index).await?;
+
+    Ok(records.len())
+}
+
+/// Leaves out files that were filtered, skipped by the download archive or failed
+async fn on_disk(target: &Target, files: Vec<PostFile>) -> Result<Vec<PostFile>> {
+    let mut kept = Vec::new();
+
+    for file in files {
+        if file.exists(target).await? {
+            kept.push(file);
+        }
+    }
+
+    Ok(kept)
+}
+
+/// Page listings only contain an excerpt of each post, so full bodies are retrieved one
+/// post at a time; a post whose body can't be retrieved keeps its excerpt
+async fn retrieve_contents(target: &Target, records: &mut [PostRecord]) {
+    let Target::Creator { user, .. } = target else {
+        return;
+    };
+
+    for record in records.iter_mut().filter(|record| is_excerpt(record)) {
+        let mut retries = 0;
+
+        let result = loop {
+            match api::post_content(target, user, &record.info.id).await {
+                Ok(content) => {
+                    break Ok(content);
+                }
+                Err(err) => {
+                    if let Err(err) = err.interpret(retries).await {
+                        break Err(err);
+                    }
+
+                    retries += 1;
+                }
+            }
+        };
+
+        match result {
+            Ok(Some(content)) => {
+                if let Some(json) = record.json.as_object_mut() {
+                    json.insert("content".to_string(), Value::String(content));
+                }
+            }
+            Ok(None) => {}
+            Err(err) => {
+                progress::suspend(||
+                    eprintln!("export: {err}, keeping the excerpt of post {}", record.info.id)
+                );
+            }
+        }
+    }
+}
+
+/// Whether only the shortened `substring` of a page listing is known
+fn is_excerpt(record: &PostRecord) -> bool {
+    record.info.section.is_none() &&
+        record.json.get("content").is_none() &&
+        record.json.get("substring").is_some()
+}
+
+async fn write_page(path: &Path, content: &str) -> Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).await.with_context(||
+            format!("create export directory: {}", dir.display())
+        )?;
+    }
+
+    fs::write(path, content).await.with_context(|| format!("write page: {}", path.display()))
+}
+
+fn title(record: &PostRecord) -> String {
+    match record.info.title.as_deref().map(str::trim) {
+        Some(title) if !title.is_empty() => title.to_string(),
+        _ => format!("Post {}", record.info.id),
+    }
+}
+
+fn published(record: &PostRecord) -> &str {
+    record.info.published.as_deref().unwrap_or("unknown date")
+}
+
+fn content(record: &PostRecord) -> &str {
+    api::content(&record.json).unwrap_or_default()
+}
+
+fn file_name(file: &PostFile) -> String {
+    file.name.clone().unwrap_or_else(|| file.to_name())
+}
+
+/// Relative, percent-encoded link from one page to another file below the target directory
+fn link(from: &Path, to: &Path) -> String {
+    let from_dir = from.parent().map(Path::to_path_buf).unwrap_or_default();
+
+    let common = from_dir
+        .components()
+        .zip(to.components())
+        .take_while(|(a, b)| a == b)
+        .count();
+
+    let mut parts: Vec<String> = from_dir
+        .components()
+        .skip(common)
+        .map(|_| "..".to_string())
+        .collect();
+
+    parts.extend(
+        to
+            .components()
+            .skip(common)
+            .filter_map(|c| match c {
+                Component::Normal(part) => Some(encode(&part.to_string_lossy())),
+                _ => None,
+            })
+    );
+
+    parts.join("/")
+}
+
+fn encode(part: &str) -> String {
+    let mut encoded = String::new();
+
+    for byte in part.bytes() {
+        if byte.is_ascii_alphanumeric() || matches!(byte, b'-' | b'_' | b'.' | b'~') {
+            encoded.push(byte as char);
+        } else {
+            let _ = write!(encoded, "%{byte:02X}");
+        }
+    }
+
+    encoded
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+fn escape_markdown(text: &str) -> String {
+    text.replace('[', "\\[").replace(']', "\\]")
+}
+
+fn post_html(target: &Target, record: &PostRecord, page: &Path, index: &Path) -> String {
+    let title = escape_html(&title(record));
+
+    let mut html = format!(
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>{title}</title>\n</head>\n<body>\n\
+         <p><a href=\"{index}\">{target}</a></p>\n<h1>{title}</h1>\n<p><time>{published}</time></p>\n\
+         <div>\n{content}\n</div>\n",
+        index = link(page, index),
+        published = escape_html(published(record)),
+        content = content(record)
+    );
+
+    if is_excerpt(record) {
+        html.push_str("<p><em>Excerpt, the full post could not be retrieved</em></p>\n");
+    }
+
+    if !record.files.is_empty() {
+        html.push_str("<h2>Files</h2>\n<ul>\n");
+
+        for file in &record.files {
+            let _ = writeln!(
+                html,
+                "<li><a href=\"{local}\">{name}</a> (<a href=\"{remote}\">remote</a>)</li>",
+                local = link(page, &file.to_pathbuf(target)),
+                name = escape_html(&file_name(file)),
+                remote = escape_html(&file.to_url(target))
+            );
+        }
+
+        html.push_str("</ul>\n");
+    }
+
+    html.push_str("</body>\n</html>\n");
+
+    html
+}
+
+fn post_markdown(target: &Target, record: &PostRecord, page: &Path, index: &Path) -> String {
+    let mut md = format!(
+        "[{target}]({index})\n\n# {title}\n\n*{published}*\n\n{content}\n",
+        index = link(page, index),
+        title = title(record),
+        published = published(record),
+        content = content(record)
+    );
+
+    if is_excerpt(record) {
+        md.push_str("\n*Excerpt, the full post could not be retrieved*\n");
+    }
+
+    if !record.files.is_empty() {
+        md.push_str("\n## Files\n\n");
+
+        for file in &record.files {
+            let _ = writeln!(
+                md,
+                "- [{name}]({local}) ([remote]({remote}))",
+                name = escape_markdown(&file_name(file)),
+                local = link(page, &file.to_pathbuf(target)),
+                remote = file.to_url(target)
+            );
+        }
+    }
+
+    md
+}
+
+fn index_html(target: &Target, entries: &[(&PostRecord, String)]) -> String {
+    let mut html = format!(
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>{target}</title>\n</head>\n<body>\n\
+         <h1>{target}</h1>\n<ul>\n"
+    );
+
+    for (record, href) in entries {
+        let _ = writeln!(
+            html,
+            "<li><time>{published}</time> <a href=\"{href}\">{title}</a> ({files})</li>",
+            published = escape_html(published(record)),
+            title = escape_html(&title(record)),
+            files = pretty::files(record.files.len())
+        );
+    }
+
+    html.push_str("</ul>\n</body>\n</html>\n");
+
+    html
+}
+
+fn index_markdown(target: &Target, entries: &[(&PostRecord, String)]) -> String {
+    let mut md = format!("# {target}\n\n");
+
+    for (record, href) in entries {
+        let _ = writeln!(
+            md,
+            "- *{published}* [{title}]({href}) ({files})",
+            published = published(record),
+            title = escape_markdown(&title(record)),
+            files = pretty::files(record.files.len())
+        );
+    }
+
+    md
+}
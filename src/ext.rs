@@ -1,12 +1,65 @@
-use crate::{ file::PostFile, target::Target };
+use crate::{ cli::ARGS, file::PostFile };
+use itertools::Itertools;
+use serde::{ Deserialize, Serialize };
 use std::collections::HashSet;
 
-pub fn list(files: HashSet<PostFile>, target: &Target) {
+/// File extensions to include or exclude
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ExtFilter {
+    Include(Vec<String>),
+    Exclude(Vec<String>),
+}
+
+impl ExtFilter {
+    pub fn from_args() -> Option<Self> {
+        ARGS.included()
+            .map(ExtFilter::Include)
+            .or_else(|| ARGS.excluded().map(ExtFilter::Exclude))
+    }
+
+    /// Parses a comma separated list of extensions
+    pub fn parse_list(list: &str) -> Vec<String> {
+        list.split(',')
+            .map(|ext| ext.trim().to_lowercase())
+            .filter(|ext| !ext.is_empty())
+            .unique()
+            .collect()
+    }
+
+    /// Filter for files matching either filter, `None` matches every file
+    pub fn union(a: Option<Self>, b: Option<Self>) -> Option<Self> {
+        use ExtFilter::{ Exclude, Include };
+
+        Some(match (a?, b?) {
+            (Include(a), Include(b)) => Include(a.into_iter().chain(b).unique().collect()),
+            (Exclude(a), Exclude(b)) => Exclude(a.into_iter().filter(|ext| b.contains(ext)).collect()),
+            (Include(include), Exclude(exclude)) | (Exclude(exclude), Include(include)) =>
+                Exclude(exclude.into_iter().filter(|ext| !include.contains(ext)).collect()),
+        })
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            ExtFilter::Include(_) => "inclusive filter",
+            ExtFilter::Exclude(_) => "exclusive filter",
+        }
+    }
+
+    pub fn matches(&self, file: &PostFile) -> bool {
+        match self {
+            ExtFilter::Include(exts) => file.to_extension().is_some_and(|ext| exts.contains(&ext)),
+            ExtFilter::Exclude(exts) => file.to_extension().is_none_or(|ext| !exts.contains(&ext)),
+        }
+    }
+}
+
+pub fn list(files: HashSet<PostFile>) {
     let mut extensions = HashSet::new();
     let mut no_ext = 0;
 
     for file in files {
-        if let Some(ext) = file.to_extension(target) {
+        if let Some(ext) = file.to_extension() {
             extensions.insert(ext.to_lowercase());
         } else {
             no_ext += 1;
@@ -21,3 +74,29 @@ pub fn list(files: HashSet<PostFile>, target: &Target) {
         eprintln!("{}", extensions.into_iter().collect::<Vec<_>>().join(","));
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{ ExtFilter::{ Exclude, Include }, * };
+
+    fn exts(list: &str) -> Vec<String> {
+        ExtFilter::parse_list(list)
+    }
+
+    #[test]
+    fn union_matches_files_of_either_filter() {
+        assert_eq!(
+            ExtFilter::union(Some(Include(exts("jpg,png"))), Some(Include(exts("png,gif")))),
+            Some(Include(exts("jpg,png,gif")))
+        );
+        assert_eq!(
+            ExtFilter::union(Some(Exclude(exts("mp4,zip"))), Some(Exclude(exts("zip,gif")))),
+            Some(Exclude(exts("zip")))
+        );
+        assert_eq!(
+            ExtFilter::union(Some(Exclude(exts("mp4,zip"))), Some(Include(exts("zip")))),
+            Some(Exclude(exts("mp4")))
+        );
+        assert_eq!(ExtFilter::union(None, Some(Include(exts("jpg")))), None);
+    }
+}
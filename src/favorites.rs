@@ -0,0 +1,71 @@
+use crate::{ api, cli::ARGS, ext::ExtFilter, target::{ Service, Site } };
+use anyhow::{ Context, Result, bail };
+use reqwest::StatusCode;
+use serde::Deserialize;
+use std::fs;
+
+/// A favorited creator, or a favorited post if `user` is present
+#[derive(Debug, Clone, Deserialize)]
+struct Favorite {
+    id: String, // "5564244",
+    service: String, // "patreon",
+    user: Option<String>, // "5564244",
+}
+
+impl Favorite {
+    fn to_url(&self) -> Result<String> {
+        let service: Service = self.service.parse()?;
+        let base = service.base_url();
+
+        Ok(match (&self.user, service) {
+            (Some(user), _) => format!("{base}/{service}/user/{user}/post/{}", self.id),
+            (None, Service::Discord) => format!("{base}/discord/server/{}", self.id),
+            (None, _) => format!("{base}/{service}/user/{}", self.id),
+        })
+    }
+}
+
+async fn fetch(site: Site, kind: &str) -> Result<Vec<Favorite>> {
+    if ARGS.session.is_none() && ARGS.cookies.is_none() {
+        bail!("a session cookie (--session or --cookies) is required to fetch favorites");
+    }
+
+    let res = api::get(site, &format!("/account/favorites?type={kind}")).await?;
+
+    match res.status() {
+        StatusCode::OK => Ok(res.json().await?),
+        StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN =>
+            bail!("failed to fetch favorites from {site}: the session is invalid or expired"),
+        status => bail!("[{status}] failed to fetch favorites from {site}"),
+    }
+}
+
+fn read(path: &str) -> Result<Vec<Favorite>> {
+    let text = fs::read_to_string(path).with_context(|| format!("read favorites: {path}"))?;
+    serde_json::from_str(&text).with_context(|| format!("parse favorites: {path}"))
+}
+
+/// Target URLs of favorited creators and posts, from the account or an exported file
+pub async fn inputs() -> Result<Vec<(String, Option<ExtFilter>)>> {
+    let mut favorites = Vec::new();
+
+    if let Some(site) = ARGS.favorites {
+        favorites.append(&mut fetch(site, "artist").await?);
+        favorites.append(&mut fetch(site, "post").await?);
+    }
+
+    if let Some(path) = &ARGS.favorites_file {
+        favorites.append(&mut read(path)?);
+    }
+
+    let mut inputs = Vec::new();
+
+    for favorite in favorites {
+        match favorite.to_url() {
+            Ok(url) => inputs.push((url, None)),
+            Err(err) => eprintln!("skipping favorite {} ({}): {err}", favorite.id, favorite.service),
+        }
+    }
+
+    Ok(inputs)
+}
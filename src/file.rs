@@ -1,30 +1,105 @@
-use crate::{ cli::ARGS, http::CLIENT, progress::DownloadAction, target::Target };
+use crate::{
+    api::PostInfo,
+    bandwidth,
+    cli::ARGS,
+    progress::{ DownloadAction, SkipReason },
+    proxy::Lease,
+    retry::{ self, Cause, MAX_RESUMES, Retries },
+    target::Target,
+    template,
+};
 use anyhow::{ Context, Result, bail };
 use futures_util::StreamExt;
 use regex::Regex;
-use reqwest::StatusCode;
+use reqwest::{ StatusCode, header::CONTENT_LENGTH };
 use serde::Deserialize;
-use std::{ error::Error, io::SeekFrom, path::PathBuf, sync::LazyLock, time::Duration };
+use std::{
+    cmp::Ordering,
+    error::Error,
+    fmt,
+    hash::{ Hash, Hasher },
+    io::SeekFrom,
+    path::PathBuf,
+    sync::{ Arc, LazyLock },
+    time::Duration,
+};
 use tokio::{
     fs::{ self, File },
     io::{ AsyncSeekExt, AsyncWriteExt },
     sync::mpsc::Sender,
-    time::sleep,
+    time::{ sleep, timeout },
 };
 
+/// A download that stopped early (timeout, stall, connection loss) and can be resumed
+/// from the current size of the temporary file
+#[derive(Debug)]
+struct Interrupted(String);
+
+impl fmt::Display for Interrupted {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "download interrupted: {}", self.0)
+    }
+}
+
+impl Error for Interrupted {}
+
+/// Timeouts and connection errors are worth another attempt
+fn interrupted(err: reqwest::Error) -> anyhow::Error {
+    if err.is_timeout() || err.is_connect() || err.is_body() || err.is_request() {
+        Interrupted(err.to_string()).into()
+    } else {
+        err.into()
+    }
+}
+
 static HASH_RE: LazyLock<Regex> = LazyLock::new(||
     Regex::new(r"^(?<hash>[0-9a-f]{64})(?:\..+)?$").unwrap()
 );
 
-#[derive(Debug, Clone, Deserialize, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[derive(Debug, Clone, Deserialize)]
 pub struct PostFile {
-    // deserializing the name breaks our hashset's uniqueness guarantee; the same file
+    pub path: Option<String>,
+
+    // comparing the name breaks our hashset's uniqueness guarantee; the same file
     // may be known under different names, leading to a race condition where multiple
     // concurrent tasks write to the same file.
     // effects: corruption, size mismatch => deletion (2nd race condition), HTTP 426
     //
-    // pub name: Option<String>,
-    pub path: Option<String>,
+    // equality, ordering and hashing are therefore implemented for `path` only, and
+    // temporary files are always named after the CDN path.
+    pub name: Option<String>,
+
+    #[serde(skip)]
+    pub post: Option<Arc<PostInfo>>,
+
+    #[serde(skip)]
+    pub index: usize,
+}
+
+impl PartialEq for PostFile {
+    fn eq(&self, other: &Self) -> bool {
+        self.path == other.path
+    }
+}
+
+impl Eq for PostFile {}
+
+impl PartialOrd for PostFile {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for PostFile {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.path.cmp(&other.path)
+    }
+}
+
+impl Hash for PostFile {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.path.hash(state);
+    }
 }
 
 impl PostFile {
@@ -34,8 +109,8 @@ impl PostFile {
 
     pub fn to_url(&self, target: &Target) -> String {
         format!(
-            "https://{site}/data{path}",
-            site = target.as_service().site(),
+            "{base}/data{path}",
+            base = target.as_service().base_url(),
             path = self.path.as_ref().unwrap()
         )
     }
@@ -52,16 +127,32 @@ impl PostFile {
         self.to_name() + ".temp"
     }
 
-    pub fn to_extension(&self, target: &Target) -> Option<String> {
-        self.to_pathbuf(target)
+    /// Final file name, built from the `--filename` template if one was given
+    pub fn to_output_name(&self) -> String {
+        ARGS.filename.as_ref().map_or_else(|| self.to_name(), |t| template::render(t, self))
+    }
+
+    pub fn to_extension(&self) -> Option<String> {
+        PathBuf::from(self.to_name())
             .extension()
             .map(|ext| ext.to_string_lossy().to_ascii_lowercase())
     }
 
+    /// Subdirectory of the target directory according to `--layout`
+    pub fn to_subdir(&self) -> Option<String> {
+        self.post.as_deref()?.to_subdir()
+    }
+
     pub fn to_pathbuf(&self, target: &Target) -> PathBuf {
-        target.to_pathbuf(Some(&self.to_name()))
+        let name = self.to_output_name();
+
+        match self.to_subdir() {
+            Some(dir) => target.to_pathbuf(Some(&dir)).join(name),
+            None => target.to_pathbuf(Some(&name)),
+        }
     }
 
+    /// Temporary files always stay in the target directory, regardless of `--layout`
     pub fn to_temp_pathbuf(&self, target: &Target) -> PathBuf {
         target.to_pathbuf(Some(&self.to_temp_name()))
     }
@@ -88,13 +179,19 @@ impl PostFile {
 
     pub async fn exists(&self, target: &Target) -> Result<bool> {
         fs::try_exists(self.to_pathbuf(target)).await.with_context(||
-            format!("check if file exists: {}", self.to_temp_name())
+            format!("check if file exists: {}", self.to_output_name())
         )
     }
 
     pub async fn r#move(&self, target: &Target) -> Result<()> {
+        if let Some(dir) = self.to_subdir() {
+            fs::create_dir_all(target.to_pathbuf(Some(&dir))).await.with_context(||
+                format!("create post directory: {dir}")
+            )?;
+        }
+
         fs::rename(self.to_temp_pathbuf(target), self.to_pathbuf(target)).await.with_context(|| {
-            format!("rename tempfile to file: {} -> {}", self.to_temp_name(), self.to_name())
+            format!("rename tempfile to file: {} -> {}", self.to_temp_name(), self.to_output_name())
         })
     }
 
@@ -104,10 +201,12 @@ impl PostFile {
         )
     }
 
+    /// Downloads the file, counting failed attempts in `retries`
     pub async fn download(
         &self,
         target: &Target,
-        mut msg_tx: Sender<DownloadAction>
+        mut msg_tx: Sender<DownloadAction>,
+        retries: &mut Retries
     ) -> Result<DownloadAction> {
         msg_tx.send(DownloadAction::Start).await?;
 
@@ -115,7 +214,17 @@ impl PostFile {
             return Ok(DownloadAction::Skip(self.to_hash()));
         }
 
-        let rsize = self.remote_size(target, &mut msg_tx).await?;
+        let mut lease = Lease::new();
+
+        let rsize = self.remote_size(target, &mut lease, &mut msg_tx, &mut retries.head).await?;
+
+        if ARGS.min_size.is_some_and(|min| rsize < min) {
+            return Ok(DownloadAction::SkipFiltered(SkipReason::TooSmall));
+        }
+
+        if ARGS.max_size.is_some_and(|max| rsize > max) {
+            return Ok(DownloadAction::SkipFiltered(SkipReason::TooLarge));
+        }
 
         let mut temp_file = self.open(target).await?;
 
@@ -141,28 +250,43 @@ impl PostFile {
                 break;
             }
 
-            if let Err(err) = self.download_range(&mut temp_file, target, csize, &mut msg_tx).await {
-                let mut error = err.to_string();
-                if let Some(source) = err.source() {
-                    error.push('\n');
-                    error.push_str(&source.to_string());
+            let result = self.download_range(
+                &mut temp_file,
+                target,
+                csize,
+                &mut lease,
+                &mut msg_tx,
+                &mut retries.get
+            ).await;
+
+            let cursor = match temp_file.seek(SeekFrom::End(0)).await {
+                Ok(cursor) => cursor,
+                Err(err) => {
+                    return Ok(DownloadAction::Fail(error_chain(&err)));
                 }
-                return Ok(DownloadAction::Fail(error));
-            }
+            };
 
-            match temp_file.seek(SeekFrom::End(0)).await {
-                Ok(cursor) => {
-                    csize = cursor;
+            if let Err(err) = result {
+                // an interrupted download that made progress is resumed right away,
+                // only attempts without any progress count as retries
+                let gave_up = if cursor == csize {
+                    retries.get >= ARGS.max_retries
+                } else {
+                    retries.resumed >= MAX_RESUMES
+                };
+
+                if !err.is::<Interrupted>() || gave_up {
+                    return Ok(DownloadAction::Fail(error_chain(err.as_ref())));
                 }
-                Err(err) => {
-                    let mut error = err.to_string();
-                    if let Some(source) = err.source() {
-                        error.push('\n');
-                        error.push_str(&source.to_string());
-                    }
-                    return Ok(DownloadAction::Fail(error));
+
+                if cursor == csize {
+                    retry(Cause::Error, None, &mut msg_tx, &mut retries.get).await?;
+                } else {
+                    retries.resumed += 1;
                 }
             }
+
+            csize = cursor;
         }
 
         Ok(
@@ -192,7 +316,9 @@ impl PostFile {
         file: &mut File,
         target: &Target,
         start: u64,
-        msg_tx: &mut Sender<DownloadAction>
+        lease: &mut Lease,
+        msg_tx: &mut Sender<DownloadAction>,
+        retries: &mut usize
     ) -> Result<()> {
         fn download_error(status: StatusCode, message: &str, url: &str) -> Result<()> {
             bail!("[{status}] download failed: {message} ({url})")
@@ -201,28 +327,47 @@ impl PostFile {
         let url = self.to_url(target);
 
         loop {
-            let response = CLIENT.get(&url)
-                .header("Range", format!("bytes={start}-"))
-                .send().await?;
+            let response = lease
+                .send(|client| client.get(&url).header("Range", format!("bytes={start}-"))).await
+                .map_err(interrupted)?;
 
             let status = response.status();
 
             if status == StatusCode::PARTIAL_CONTENT {
                 let mut stream = response.bytes_stream();
 
-                while let Some(Ok(bytes)) = stream.next().await {
-                    file.write_all(&bytes).await?;
-                    msg_tx.send(DownloadAction::ReportSize(bytes.len() as u64)).await?;
-                }
+                let result = loop {
+                    match timeout(ARGS.stall_timeout, stream.next()).await {
+                        Ok(Some(Ok(bytes))) => {
+                            bandwidth::throttle(bytes.len()).await;
+                            file.write_all(&bytes).await?;
+                            msg_tx.send(DownloadAction::ReportSize(bytes.len() as u64)).await?;
+                        }
+                        Ok(Some(Err(err))) => break Err(interrupted(err)),
+                        Ok(None) => break Ok(()),
+                        Err(_) =>
+                            break Err(
+                                Interrupted(
+                                    format!(
+                                        "no data received for {}s",
+                                        ARGS.stall_timeout.as_secs()
+                                    )
+                                ).into()
+                            ),
+                    }
+                };
+
+                // keep what was received, so the next attempt can resume from here
                 file.flush().await?;
 
-                break Ok(());
+                break result;
             } else if status == StatusCode::NOT_FOUND {
                 download_error(status, "no file", &url)?;
-            } else if status == StatusCode::FORBIDDEN || status == StatusCode::TOO_MANY_REQUESTS {
-                wait(ARGS.rate_limit_backoff, msg_tx).await?;
-            } else if status.is_server_error() {
-                wait(ARGS.server_error_delay, msg_tx).await?;
+            } else if let Some(cause) = Cause::from_status(status) {
+                if *retries >= ARGS.max_retries {
+                    download_error(status, &format!("gave up after {retries} retries"), &url)?;
+                }
+                retry(cause, retry::retry_after(response.headers()), msg_tx, retries).await?;
             } else {
                 download_error(status, "unexpected status code", &url)?;
             }
@@ -232,7 +377,9 @@ impl PostFile {
     pub async fn remote_size(
         &self,
         target: &Target,
-        msg_tx: &mut Sender<DownloadAction>
+        lease: &mut Lease,
+        msg_tx: &mut Sender<DownloadAction>,
+        retries: &mut usize
     ) -> Result<u64> {
         fn size_error(status: StatusCode, message: &str, url: &str) -> Result<u64> {
             bail!("[{status}] remote size determination failed: {message} ({url})")
@@ -241,23 +388,36 @@ impl PostFile {
         let url = self.to_url(target);
 
         loop {
-            let response = CLIENT.head(&url).send().await?;
+            let response = match lease.send(|client| client.head(&url)).await.map_err(interrupted) {
+                Ok(response) => response,
+                Err(err) if err.is::<Interrupted>() && *retries < ARGS.max_retries => {
+                    retry(Cause::Error, None, msg_tx, retries).await?;
+                    continue;
+                }
+                Err(err) => {
+                    return Err(err);
+                }
+            };
 
             let status = response.status();
 
             if status == StatusCode::OK {
+                // the body size hint of a HEAD response is 0 over HTTP/1.1
                 return response
-                    .content_length()
+                    .headers()
+                    .get(CONTENT_LENGTH)
+                    .and_then(|length| length.to_str().ok()?.parse().ok())
                     .map_or_else(
                         || size_error(status, "Content-Length header is not present", &url),
                         Ok
                     );
             } else if status == StatusCode::NOT_FOUND {
                 size_error(status, "file not found", &url)?;
-            } else if status == StatusCode::FORBIDDEN || status == StatusCode::TOO_MANY_REQUESTS {
-                wait(ARGS.rate_limit_backoff, msg_tx).await?;
-            } else if status.is_server_error() {
-                wait(ARGS.server_error_delay, msg_tx).await?;
+            } else if let Some(cause) = Cause::from_status(status) {
+                if *retries >= ARGS.max_retries {
+                    size_error(status, &format!("gave up after {retries} retries"), &url)?;
+                }
+                retry(cause, retry::retry_after(response.headers()), msg_tx, retries).await?;
             } else {
                 size_error(status, "unexpected status code", &url)?;
             }
@@ -265,9 +425,26 @@ impl PostFile {
     }
 }
 
-async fn wait(duration: Duration, msg_tx: &mut Sender<DownloadAction>) -> Result<()> {
+/// Error message including its source, if any
+fn error_chain(err: &(dyn Error + 'static)) -> String {
+    let mut error = err.to_string();
+    if let Some(source) = err.source() {
+        error.push('\n');
+        error.push_str(&source.to_string());
+    }
+    error
+}
+
+/// Waits according to the retry policy, counting the retry
+async fn retry(
+    cause: Cause,
+    retry_after: Option<Duration>,
+    msg_tx: &mut Sender<DownloadAction>,
+    retries: &mut usize
+) -> Result<()> {
     msg_tx.send(DownloadAction::Wait).await?;
-    sleep(duration).await;
+    sleep(retry::delay(cause, *retries, retry_after)).await;
     msg_tx.send(DownloadAction::Continue).await?;
+    *retries += 1;
     Ok(())
 }
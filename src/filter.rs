@@ -0,0 +1,176 @@
+use crate::{ api::PostInfo, cli::ARGS, target::Target };
+use anyhow::{ Context, Result };
+use jiff::civil::DateTime;
+use regex::Regex;
+use std::{ io::ErrorKind, sync::LazyLock };
+use tokio::fs;
+
+static TITLE_MATCH: LazyLock<Option<Regex>> = LazyLock::new(||
+    compile(ARGS.title_match.as_ref())
+);
+static TITLE_EXCLUDE: LazyLock<Option<Regex>> = LazyLock::new(||
+    compile(ARGS.title_exclude.as_ref())
+);
+static CONTENT_MATCH: LazyLock<Option<Regex>> = LazyLock::new(||
+    compile(ARGS.content_match.as_ref())
+);
+
+fn compile(re: Option<&String>) -> Option<Regex> {
+    re.map(|re| Regex::new(re).expect("regex validated by argument parser"))
+}
+
+static RE_DATE: LazyLock<Regex> = LazyLock::new(||
+    Regex::new(r"^[0-9]{4}-[0-9]{2}-[0-9]{2}(?:T[0-9]{2}:[0-9]{2}(?::[0-9]{2}(?:\.[0-9]+)?)?)?$").unwrap()
+);
+
+/// Validates a date argument (`YYYY-MM-DD[THH:MM[:SS]]`)
+pub fn parse_date(arg: &str) -> Result<String, String> {
+    if RE_DATE.is_match(arg) && to_datetime(arg).is_some() {
+        Ok(arg.to_string())
+    } else {
+        Err("expected a date like 2024-05-21 or 2024-05-21T17:19:18".to_string())
+    }
+}
+
+/// Parses a date argument or an API date (ISO 8601 without a time zone), a missing
+/// time is midnight
+pub fn to_datetime(date: &str) -> Option<DateTime> {
+    date.parse().ok()
+}
+
+/// Whether `date` is later than `than`, dates that can't be parsed are never later
+pub fn is_later(date: &str, than: Option<&str>) -> bool {
+    to_datetime(date).is_some_and(|date| than.and_then(to_datetime).is_none_or(|than| date > than))
+}
+
+/// Validates a regular expression argument
+pub fn parse_regex(arg: &str) -> Result<String, String> {
+    Regex::new(arg).map(|_| arg.to_string()).map_err(|err| err.to_string())
+}
+
+/// Checks a post's title and content against `--title-match`, `--title-exclude` and
+/// `--content-match`; a missing title or content counts as empty.
+pub fn matches_text(title: Option<&str>, content: Option<&str>) -> bool {
+    let title = title.unwrap_or_default();
+    let content = content.unwrap_or_default();
+
+    TITLE_MATCH.as_ref().is_none_or(|re| re.is_match(title)) &&
+        TITLE_EXCLUDE.as_ref().is_none_or(|re| !re.is_match(title)) &&
+        CONTENT_MATCH.as_ref().is_none_or(|re| re.is_match(content))
+}
+
+/// Publication date range of posts to download
+pub struct DateRange {
+    after: Option<DateTime>,
+    before: Option<DateTime>,
+}
+
+impl DateRange {
+    /// Combines `--after`/`--before` with the date saved by `--since-last-run`
+    pub async fn new(target: &Target) -> Result<Self> {
+        let mut after = ARGS.after.as_deref().and_then(to_datetime);
+
+        if ARGS.since_last_run && let Some(last_run) = read_last_run(target).await? {
+            after = after.max(to_datetime(&last_run));
+        }
+
+        Ok(Self { after, before: ARGS.before.as_deref().and_then(to_datetime) })
+    }
+
+    /// Posts without a (valid) date are always included
+    pub fn contains(&self, post: &PostInfo) -> bool {
+        let Some(date) = post.date().and_then(to_datetime) else {
+            return true;
+        };
+
+        self.after.is_none_or(|after| date >= after) &&
+            self.before.is_none_or(|before| date < before)
+    }
+
+    /// Whether older posts are skipped, see `is_past`
+    pub fn skips_older(&self) -> bool {
+        self.after.is_some()
+    }
+
+    /// Whether a post is older than the range; posts are listed newest first,
+    /// so no later page can contain matching posts.
+    pub fn is_past(&self, post: &PostInfo) -> bool {
+        match (self.after, post.date().and_then(to_datetime)) {
+            (Some(after), Some(date)) => date < after,
+            _ => false,
+        }
+    }
+}
+
+async fn read_last_run(target: &Target) -> Result<Option<String>> {
+    let path = target.to_last_run_pathbuf();
+
+    match fs::read_to_string(&path).await {
+        Ok(date) => Ok(parse_date(date.trim()).ok()),
+        Err(err) if err.kind() == ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err).with_context(|| format!("read last run: {}", path.display())),
+    }
+}
+
+/// Saves the date of the newest post for `--since-last-run`
+pub async fn write_last_run(target: &Target, newest: &str) -> Result<()> {
+    if !is_later(newest, read_last_run(target).await?.as_deref()) {
+        return Ok(());
+    }
+
+    let path = target.to_last_run_pathbuf();
+
+    fs::write(&path, newest).await.with_context(|| format!("write last run: {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn post(date: &str) -> PostInfo {
+        PostInfo { published: Some(date.to_string()), ..PostInfo::default() }
+    }
+
+    fn range(after: Option<&str>, before: Option<&str>) -> DateRange {
+        DateRange { after: after.and_then(to_datetime), before: before.and_then(to_datetime) }
+    }
+
+    #[test]
+    fn parses_dates() {
+        assert!(parse_date("2024-05-21").is_ok());
+        assert!(parse_date("2024-05-21T17:19").is_ok());
+        assert!(parse_date("2024-05-21T17:19:18").is_ok());
+        assert!(parse_date("2024-13-21").is_err());
+        assert!(parse_date("2024-02-30").is_err());
+        assert!(parse_date("21.05.2024").is_err());
+    }
+
+    #[test]
+    fn compares_dates_not_strings() {
+        let range = range(Some("2024-05-21T09:00"), Some("2024-05-22"));
+
+        assert!(range.contains(&post("2024-05-21T09:00:00")));
+        assert!(range.contains(&post("2024-05-21T17:19:18.5")));
+        assert!(!range.contains(&post("2024-05-21T08:59:59.971848")));
+        assert!(!range.contains(&post("2024-05-22T00:00:00")));
+        assert!(range.is_past(&post("2024-05-20T23:59:59")));
+        assert!(!range.is_past(&post("2024-05-21T09:00:00")));
+    }
+
+    #[test]
+    fn includes_posts_without_a_valid_date() {
+        let range = range(Some("2024-05-21"), None);
+
+        assert!(range.contains(&PostInfo::default()));
+        assert!(range.contains(&post("unknown")));
+        assert!(!range.is_past(&post("unknown")));
+    }
+
+    #[test]
+    fn finds_later_dates() {
+        assert!(is_later("2024-05-21T17:19:18", None));
+        assert!(is_later("2024-05-21T17:19:18.1", Some("2024-05-21T17:19:18")));
+        assert!(!is_later("2024-05-21", Some("2024-05-21T00:00:00")));
+        assert!(!is_later("unknown", None));
+    }
+}
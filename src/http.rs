@@ -1,25 +1,135 @@
-use crate::cli::ARGS;
-use reqwest::{ Client, ClientBuilder, Proxy, header::{ HeaderMap, HeaderValue } };
-use std::{ process, sync::LazyLock };
+use crate::{ cli::ARGS, target::Site };
+use anyhow::{ Context, Result };
+use clap::ValueEnum;
+use reqwest::{
+    Client,
+    ClientBuilder,
+    Proxy,
+    Url,
+    cookie::Jar,
+    header::{ HeaderMap, HeaderValue },
+};
+use std::{ fs, process, sync::{ Arc, LazyLock } };
 
-pub static CLIENT: LazyLock<Client> = LazyLock::new(|| {
+pub static CLIENT: LazyLock<Client> = LazyLock::new(||
+    build_client(ARGS.proxy.as_deref()).unwrap_or_else(|err| {
+        eprintln!("{err:#}");
+        process::exit(1);
+    })
+);
+
+static COOKIES: LazyLock<Option<Arc<Jar>>> = LazyLock::new(|| {
+    if ARGS.cookies.is_none() && ARGS.session.is_none() {
+        return None;
+    }
+
+    Some(
+        Arc::new(
+            cookie_jar().unwrap_or_else(|err| {
+                eprintln!("{err:#}");
+                process::exit(1);
+            })
+        )
+    )
+});
+
+/// HTTPS is enforced unless a site or the API was configured with another scheme
+fn https_only() -> bool {
+    site_urls().iter().all(|url| url.scheme() == "https") &&
+        [&ARGS.api_base, &ARGS.kemono_api, &ARGS.coomer_api]
+            .into_iter()
+            .flatten()
+            .all(|base| base.starts_with("https://"))
+}
+
+/// Client with the configured timeouts and cookies, using the given proxy
+pub fn build_client(proxy: Option<&str>) -> Result<Client> {
     let mut headers = HeaderMap::new();
     headers.insert("Accept", HeaderValue::from_static("Text/CSS"));
 
     let mut client = ClientBuilder::new()
         .default_headers(headers)
         .connect_timeout(ARGS.connect_timeout)
-        .timeout(ARGS.read_timeout)
-        .https_only(true);
+        // a deadline for whole responses would cut off large downloads
+        .read_timeout(ARGS.read_timeout)
+        .https_only(https_only());
 
-    if let Some(proxy) = &ARGS.proxy {
-        client = client.proxy(
-            Proxy::all(proxy).unwrap_or_else(|err| {
-                eprintln!("{err}");
-                process::exit(1);
-            })
-        );
+    if let Some(proxy) = proxy {
+        client = client.proxy(Proxy::all(proxy).with_context(|| format!("proxy: {proxy}"))?);
     }
 
-    client.build().unwrap()
-});
+    if let Some(jar) = COOKIES.as_ref() {
+        client = client.cookie_provider(jar.clone());
+    }
+
+    Ok(client.build()?)
+}
+
+/// Base URLs of all configured domains of both sites
+fn site_urls() -> Vec<Url> {
+    Site::value_variants()
+        .iter()
+        .flat_map(|site| site.base_urls())
+        .filter_map(|base| Url::parse(&format!("{base}/")).ok())
+        .collect()
+}
+
+/// Cookies from `--cookies` and `--session`, limited to the coomer and kemono domains
+fn cookie_jar() -> Result<Jar> {
+    let jar = Jar::default();
+
+    if let Some(path) = &ARGS.cookies {
+        let text = fs::read_to_string(path).with_context(|| format!("read cookies: {path}"))?;
+
+        for line in text.lines() {
+            // curl marks HttpOnly cookies with a prefix that looks like a comment
+            let line = line.strip_prefix("#HttpOnly_").unwrap_or(line);
+
+            if line.trim().is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            // domain, include subdomains, path, secure, expiry, name, value
+            let fields: Vec<&str> = line.split('\t').collect();
+
+            let [domain, _, path, _, _, name, value] = fields[..] else {
+                continue;
+            };
+
+            let host = domain.trim_start_matches('.');
+
+            for url in site_urls() {
+                let site_domain = url.host_str().unwrap_or_default();
+
+                if site_domain == host || site_domain.ends_with(&format!(".{host}")) {
+                    jar.add_cookie_str(
+                        &format!("{name}={value}; Domain={host}; Path={path}"),
+                        &url
+                    );
+                }
+            }
+        }
+    }
+
+    if let Some(session) = &ARGS.session {
+        for url in site_urls() {
+            jar.add_cookie_str(
+                &format!(
+                    "{}; Domain={}; Path=/",
+                    session_cookie(session),
+                    url.host_str().unwrap_or_default()
+                ),
+                &url
+            );
+        }
+    }
+
+    Ok(jar)
+}
+
+/// Session cookie, accepted with or without the `session=` prefix
+pub fn session_cookie(session: &str) -> String {
+    let session = session.trim();
+    let session = session.strip_prefix("session=").unwrap_or(session);
+    format!("session={session}")
+}
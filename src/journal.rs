@@ -0,0 +1,607 @@
+use crate::{
+    api::PostInfo,
+    cli::{ ARGS, Extra, Layout },
+    ext::ExtFilter,
+    file::PostFile,
+    filter,
+    pretty,
+    sync,
+    target::{ SubType, Target },
+};
+use anyhow::{ Context, Result, bail };
+use clap::ValueEnum;
+use serde::{ Deserialize, Serialize };
+use std::{
+    collections::{ BTreeMap, HashMap, HashSet },
+    fs::{ self, File },
+    io::{ BufWriter, ErrorKind, Write },
+    path::PathBuf,
+    process::exit,
+    sync::{ Arc, Mutex },
+};
+
+/// Journal of the current run, `None` for dry runs, listing extensions and runs that
+/// only consist of single posts or pages. Entries are written out at the end of every
+/// target, see `flush`.
+static JOURNAL: Mutex<Option<BufWriter<File>>> = Mutex::new(None);
+
+/// Files left to download per target number, for targets that are not indexed again
+static PENDING: Mutex<BTreeMap<usize, Pending>> = Mutex::new(BTreeMap::new());
+
+/// Journal of the last run, so that `kumono resume` can pick up where it stopped
+pub fn pathbuf() -> PathBuf {
+    PathBuf::from_iter([&ARGS.output_path, "db", "journal.jsonl"])
+}
+
+/// A target as it was resolved, so that resuming doesn't depend on its URL
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+enum Resolved {
+    Creator {
+        service: String,
+        user: String,
+        post: Option<String>,
+        offset: Option<usize>,
+        section: Option<Extra>,
+    },
+    Discord {
+        server: String,
+        channel: Option<String>,
+    },
+}
+
+impl Resolved {
+    fn new(target: &Target) -> Self {
+        match target {
+            Target::Creator { service, user, subtype, .. } => {
+                let (mut post, mut offset, mut section) = (None, None, None);
+
+                match subtype {
+                    SubType::Post(id) => {
+                        post = Some(id.clone());
+                    }
+                    SubType::PageOffset(o) => {
+                        offset = Some(*o);
+                    }
+                    SubType::Section(s) => {
+                        section = Some(*s);
+                    }
+                    SubType::None => {}
+                }
+
+                Resolved::Creator { service: service.to_string(), user: user.clone(), post, offset, section }
+            }
+            Target::Discord { server, channel, .. } =>
+                Resolved::Discord { server: server.clone(), channel: channel.clone() },
+        }
+    }
+
+    /// The target without its download archive, which is read separately
+    fn to_target(&self, exts: Option<ExtFilter>) -> Result<Target> {
+        Ok(match self {
+            Resolved::Creator { service, user, post, offset, section } =>
+                Target::Creator {
+                    service: service.parse()?,
+                    user: user.clone(),
+                    subtype: match (post, offset, section) {
+                        (Some(post), _, _) => SubType::Post(post.clone()),
+                        (_, Some(offset), _) => SubType::PageOffset(*offset),
+                        (_, _, Some(section)) => SubType::Section(*section),
+                        _ => SubType::None,
+                    },
+                    archive: Vec::new(),
+                    exts,
+                },
+            Resolved::Discord { server, channel } =>
+                Target::Discord {
+                    server: server.clone(),
+                    channel: channel.clone(),
+                    archive: Vec::new(),
+                    exts,
+                },
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct JobTarget {
+    #[serde(flatten)]
+    target: Resolved,
+    exts: Option<ExtFilter>,
+    /// Update date for `sync`, saved once the target was downloaded
+    updated: Option<String>,
+}
+
+/// Targets of a run, along with the options that determine where files are saved
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct Job {
+    targets: Vec<JobTarget>,
+    layout: Layout,
+    filename: Option<String>,
+}
+
+/// A line of the journal; targets are referred to by their number (from 1)
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+enum Entry {
+    /// Targets of the run, always the first line
+    Job(Job),
+    /// A file was found while indexing
+    File {
+        target: usize,
+        path: String,
+        name: Option<String>,
+        index: usize,
+        post: Option<PostInfo>,
+        section: Option<Extra>,
+    },
+    /// All posts of a target were retrieved
+    Indexed {
+        target: usize,
+        posts: usize,
+        files: usize,
+        newest: Option<String>,
+    },
+    /// A file was downloaded or skipped
+    Done {
+        target: usize,
+        path: String,
+    },
+}
+
+impl Entry {
+    fn from_file(target: usize, file: &PostFile) -> Option<Self> {
+        Some(Entry::File {
+            target,
+            path: file.path.clone()?,
+            name: file.name.clone(),
+            index: file.index,
+            post: file.post.as_deref().cloned(),
+            section: file.post.as_deref().and_then(|post| post.section),
+        })
+    }
+}
+
+/// Files of an indexed target that were not downloaded yet
+pub struct Pending {
+    pub files: Vec<PostFile>,
+    pub posts: usize,
+    pub total: usize,
+    pub newest: Option<String>,
+}
+
+/// What the journal knows about a target
+#[derive(Debug, Default)]
+struct Progress {
+    files: Vec<PostFile>,
+    done: HashSet<String>,
+    indexed: Option<(usize, usize, Option<String>)>,
+}
+
+impl Progress {
+    /// Files that were found but not downloaded yet
+    fn left(self) -> Vec<PostFile> {
+        self.files
+            .into_iter()
+            .filter(|file| file.path.as_ref().is_some_and(|path| !self.done.contains(path)))
+            .collect()
+    }
+}
+
+fn write(entry: &Entry) {
+    let mut journal = JOURNAL.lock().expect("lock journal");
+
+    let Some(file) = journal.as_mut() else {
+        return;
+    };
+
+    let mut line = serde_json::to_string(entry).expect("serialize journal entry");
+    line.push('\n');
+
+    // a broken journal only affects `kumono resume`, the downloads can go on
+    if let Err(err) = file.write_all(line.as_bytes()) {
+        eprintln!("journal: {err}, resuming this run will not be possible");
+        *journal = None;
+    }
+}
+
+/// Writes out the buffered entries, at the end of every target
+pub fn flush() {
+    let mut journal = JOURNAL.lock().expect("lock journal");
+
+    if let Some(file) = journal.as_mut() && let Err(err) = file.flush() {
+        eprintln!("journal: {err}, resuming this run will not be possible");
+        *journal = None;
+    }
+}
+
+/// Single posts and pages are retrieved again quickly, so they are not worth a journal
+fn is_worth_resuming(target: &Target) -> bool {
+    !matches!(target, Target::Creator { subtype: SubType::Post(_) | SubType::PageOffset(_), .. })
+}
+
+/// Starts a new journal for the given targets, replacing the one of the last run, unless
+/// none of them is worth resuming
+pub fn start(targets: &[(Target, Option<String>)]) -> Result<()> {
+    if targets.iter().any(|(target, _)| is_worth_resuming(target)) {
+        create(targets)?;
+    }
+
+    Ok(())
+}
+
+fn create(targets: &[(Target, Option<String>)]) -> Result<()> {
+    fs::create_dir_all(PathBuf::from_iter([&ARGS.output_path, "db"]))?;
+
+    let file = File::create(pathbuf()).context("create journal")?;
+
+    *JOURNAL.lock().expect("lock journal") = Some(BufWriter::new(file));
+
+    write(
+        &Entry::Job(Job {
+            targets: targets
+                .iter()
+                .map(|(target, updated)| JobTarget {
+                    target: Resolved::new(target),
+                    exts: target.exts(),
+                    updated: updated.clone(),
+                })
+                .collect(),
+            layout: ARGS.layout,
+            filename: ARGS.filename.clone(),
+        })
+    );
+
+    flush();
+
+    Ok(())
+}
+
+pub fn file(target: usize, file: &PostFile) {
+    if let Some(entry) = Entry::from_file(target, file) {
+        write(&entry);
+    }
+}
+
+/// Also writes out the buffered files, so that resuming can skip indexing the target
+pub fn indexed(target: usize, posts: usize, files: usize, newest: Option<&str>) {
+    write(&(Entry::Indexed { target, posts, files, newest: newest.map(ToString::to_string) }));
+    flush();
+}
+
+pub fn done(target: usize, file: &PostFile) {
+    if let Some(path) = &file.path {
+        write(&(Entry::Done { target, path: path.clone() }));
+    }
+}
+
+/// Files left to download for a target that was indexed before
+pub fn take_pending(target: usize) -> Option<Pending> {
+    PENDING.lock().expect("lock pending files").remove(&target)
+}
+
+/// Removes the journal once every download succeeded, so that there is nothing to resume
+pub fn finish(failed: bool) -> Result<()> {
+    flush();
+
+    if JOURNAL.lock().expect("lock journal").take().is_none() {
+        return Ok(());
+    }
+
+    if failed {
+        eprintln!("use `kumono resume` to retry the failed downloads");
+        return Ok(());
+    }
+
+    fs::remove_file(pathbuf()).context("remove journal")
+}
+
+/// Reads the journal of the last run, returning its targets that are not done yet.
+///
+/// Targets that were indexed completely keep their remaining files, the others are
+/// indexed again. The journal is rewritten with what is left, and temporary files of
+/// downloads that are no longer queued are removed.
+pub async fn resume() -> Result<Vec<(Target, Option<String>)>> {
+    if ARGS.dry_run || ARGS.list_extensions {
+        bail!("resume: --dry-run and --list-extensions are not supported");
+    }
+
+    let path = pathbuf();
+
+    let text = match fs::read_to_string(&path) {
+        Ok(text) => text,
+        Err(err) if err.kind() == ErrorKind::NotFound => {
+            eprintln!("resume: there is no run to resume");
+            exit(1);
+        }
+        Err(err) => {
+            return Err(err).context("read journal");
+        }
+    };
+
+    let Some((job, mut progress)) = read(&text) else {
+        bail!("invalid journal: {}", path.display());
+    };
+
+    if job.layout != ARGS.layout || job.filename != ARGS.filename {
+        bail!(
+            "resume: the last run used --layout {}{}, pass the same options to resume it",
+            job.layout.to_possible_value().expect("layout name").get_name(),
+            job.filename.map_or(" without --filename".to_string(), |filename| {
+                format!(" --filename '{filename}'")
+            })
+        );
+    }
+
+    let mut targets = Vec::new();
+    let mut pending = BTreeMap::new();
+    let mut queued: HashMap<PathBuf, HashSet<String>> = HashMap::new();
+    let mut reindexed = HashSet::new();
+
+    for (i, JobTarget { target, exts, updated }) in job.targets.into_iter().enumerate() {
+        let mut target = match target.to_target(exts) {
+            Ok(target) => target,
+            Err(err) => {
+                eprintln!("resume: {err}");
+                continue;
+            }
+        };
+
+        if ARGS.download_archive {
+            target.read_archive()?;
+        }
+
+        let progress = progress.remove(&(i + 1)).unwrap_or_default();
+        let indexed = progress.indexed.clone();
+        let left = progress.left();
+
+        queued
+            .entry(target.to_pathbuf(None))
+            .or_default()
+            .extend(left.iter().map(PostFile::to_temp_name));
+
+        if let Some((posts, total, newest)) = indexed {
+            // everything was downloaded, only the dates of a finished target are missing
+            if left.is_empty() {
+                if ARGS.since_last_run && target.is_whole() && let Some(newest) = &newest {
+                    filter::write_last_run(&target, newest).await?;
+                }
+
+                if let Some(updated) = &updated {
+                    sync::write_updated(&target, updated)?;
+                }
+
+                continue;
+            }
+
+            pending.insert(targets.len() + 1, Pending { files: left, posts, total, newest });
+        } else {
+            // files found again while indexing continue their temporary files
+            reindexed.insert(target.to_pathbuf(None));
+        }
+
+        targets.push((target, updated));
+    }
+
+    queued.retain(|dir, _| !reindexed.contains(dir));
+
+    let removed = remove_stale_temp_files(&queued);
+
+    if removed > 0 {
+        eprintln!("resume: removed {} of downloads that are no longer queued", pretty::anything(
+            removed,
+            "temporary file",
+            "temporary files"
+        ));
+    }
+
+    if targets.is_empty() {
+        fs::remove_file(&path).context("remove journal")?;
+        eprintln!("resume: nothing to do");
+        exit(0);
+    }
+
+    // the journal is rewritten with the remaining work only
+    create(&targets)?;
+
+    for (id, Pending { files, posts, total, newest }) in &pending {
+        for pending_file in files {
+            file(*id, pending_file);
+        }
+
+        indexed(*id, *posts, *total, newest.as_deref());
+    }
+
+    *PENDING.lock().expect("lock pending files") = pending;
+
+    Ok(targets)
+}
+
+/// Parses a journal into its job and the progress per target number, `None` if it
+/// doesn't start with a job
+fn read(text: &str) -> Option<(Job, HashMap<usize, Progress>)> {
+    let mut lines = text.lines();
+
+    let Ok(Entry::Job(job)) = serde_json::from_str(lines.next()?) else {
+        return None;
+    };
+
+    let mut progress: HashMap<usize, Progress> = HashMap::new();
+
+    // the last line may be incomplete if the run was killed while writing it
+    for entry in lines.filter_map(|line| serde_json::from_str::<Entry>(line).ok()) {
+        match entry {
+            Entry::Job(_) => {
+                return None;
+            }
+            Entry::File { target, path, name, index, post, section } =>
+                progress
+                    .entry(target)
+                    .or_default()
+                    .files.push(PostFile {
+                        path: Some(path),
+                        name,
+                        post: post.map(|post| Arc::new(PostInfo { section, ..post })),
+                        index,
+                    }),
+            Entry::Indexed { target, posts, files, newest } => {
+                progress.entry(target).or_default().indexed = Some((posts, files, newest));
+            }
+            Entry::Done { target, path } => {
+                progress.entry(target).or_default().done.insert(path);
+            }
+        }
+    }
+
+    Some((job, progress))
+}
+
+/// Removes temporary files in target directories that don't belong to a queued download
+fn remove_stale_temp_files(queued: &HashMap<PathBuf, HashSet<String>>) -> usize {
+    let mut removed = 0;
+
+    for (dir, names) in queued {
+        let Ok(entries) = fs::read_dir(dir) else {
+            continue;
+        };
+
+        for entry in entries.flatten() {
+            let name = entry.file_name().to_string_lossy().to_string();
+
+            if
+                name.ends_with(".temp") &&
+                !names.contains(&name) &&
+                fs::remove_file(entry.path()).is_ok()
+            {
+                removed += 1;
+            }
+        }
+    }
+
+    removed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::target::Service;
+
+    fn post_file(path: &str, post: Option<PostInfo>) -> PostFile {
+        PostFile { path: Some(path.to_string()), name: None, post: post.map(Arc::new), index: 0 }
+    }
+
+    fn line(entry: &Entry) -> String {
+        serde_json::to_string(entry).unwrap()
+    }
+
+    fn job(targets: Vec<JobTarget>) -> Job {
+        Job { targets, layout: Layout::Flat, filename: Some("{post}-{name}".to_string()) }
+    }
+
+    #[test]
+    fn resolved_targets_round_trip() {
+        let subtypes = [
+            SubType::None,
+            SubType::Post("42".to_string()),
+            SubType::PageOffset(50),
+            SubType::Section(Extra::Fancards),
+        ];
+
+        for subtype in subtypes {
+            let target = Target::Creator {
+                service: Service::Patreon,
+                user: "1".to_string(),
+                subtype,
+                archive: Vec::new(),
+                exts: Some(ExtFilter::Include(vec!["png".to_string()])),
+            };
+
+            assert_eq!(Resolved::new(&target).to_target(target.exts()).unwrap(), target);
+        }
+
+        let target = Target::Discord {
+            server: "10".to_string(),
+            channel: Some("20".to_string()),
+            archive: Vec::new(),
+            exts: None,
+        };
+
+        assert_eq!(Resolved::new(&target).to_target(None).unwrap(), target);
+    }
+
+    #[test]
+    fn reads_back_what_was_written() {
+        let post = PostInfo {
+            id: "7".to_string(),
+            title: Some("title".to_string()),
+            section: Some(Extra::Announcements),
+            ..PostInfo::default()
+        };
+        let found = post_file("/a/b/one.png", Some(post));
+        let targets = vec![JobTarget {
+            target: Resolved::Discord { server: "10".to_string(), channel: None },
+            exts: Some(ExtFilter::Exclude(vec!["zip".to_string()])),
+            updated: Some("2024-05-21T17:19:18".to_string()),
+        }];
+
+        let text = [
+            line(&Entry::Job(job(targets.clone()))),
+            line(&Entry::from_file(1, &found).unwrap()),
+            line(&(Entry::Indexed { target: 1, posts: 1, files: 1, newest: None })),
+        ].join("\n");
+
+        let (read_job, mut progress) = read(&text).unwrap();
+
+        assert_eq!(read_job, job(targets));
+
+        let Progress { files, done, indexed } = progress.remove(&1).unwrap();
+
+        assert_eq!(indexed, Some((1, 1, None)));
+        assert!(done.is_empty());
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].path, found.path);
+        assert_eq!(files[0].post, found.post);
+    }
+
+    #[test]
+    fn leaves_out_done_files_and_a_truncated_line() {
+        let text = [
+            line(&Entry::Job(job(Vec::new()))),
+            line(&Entry::from_file(1, &post_file("/one.png", None)).unwrap()),
+            line(&Entry::from_file(1, &post_file("/two.png", None)).unwrap()),
+            line(&(Entry::Done { target: 1, path: "/one.png".to_string() })),
+            r#"{"event":"done","target":1,"pa"#.to_string(),
+        ].join("\n");
+
+        let (_, mut progress) = read(&text).unwrap();
+        let progress = progress.remove(&1).unwrap();
+
+        assert_eq!(progress.indexed, None);
+        assert_eq!(progress.left(), vec![post_file("/two.png", None)]);
+    }
+
+    #[test]
+    fn rejects_a_journal_without_a_job() {
+        assert!(read("").is_none());
+        assert!(read(&line(&(Entry::Done { target: 1, path: "/one.png".to_string() }))).is_none());
+    }
+
+    #[test]
+    fn removes_only_stale_temp_files() {
+        let dir = std::env::temp_dir().join(format!("kumono-journal-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        for name in ["queued.png.temp", "stale.png.temp", "done.png"] {
+            fs::write(dir.join(name), b"").unwrap();
+        }
+
+        let queued = HashMap::from([(dir.clone(), HashSet::from(["queued.png.temp".to_string()]))]);
+
+        assert_eq!(remove_stale_temp_files(&queued), 1);
+        assert!(dir.join("queued.png.temp").exists());
+        assert!(!dir.join("stale.png.temp").exists());
+        assert!(dir.join("done.png").exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
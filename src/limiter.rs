@@ -0,0 +1,179 @@
+use crate::cli::ARGS;
+use reqwest::{ RequestBuilder, Response, StatusCode };
+use std::{ collections::{ HashMap, VecDeque }, sync::{ LazyLock, Mutex } };
+use tokio::time::{ Duration, Instant, sleep };
+
+/// Number of 403/429 responses within `BURST_WINDOW` that pause all requests
+const BURST_SIZE: usize = 10;
+
+const BURST_WINDOW: Duration = Duration::from_secs(10);
+
+/// API requests per second per API without `--rate-limit`, so that pages retrieved at once
+/// (`--page-concurrency`) don't hit the API in bursts
+const DEFAULT_API_RATE: f64 = 5.0;
+
+static LIMITER: LazyLock<Mutex<Limiter>> = LazyLock::new(|| Mutex::new(Limiter::default()));
+
+/// Token bucket per host, allowing short bursts of up to one second's worth of requests
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+#[derive(Default)]
+struct Limiter {
+    buckets: HashMap<String, Bucket>,
+    rejections: VecDeque<Instant>,
+    paused_until: Option<Instant>,
+}
+
+impl Limiter {
+    /// Takes a token for `host`, or returns how long to wait for one
+    fn take(&mut self, host: &str, rate: f64) -> Option<Duration> {
+        self.take_at(host, rate, Instant::now())
+    }
+
+    fn take_at(&mut self, host: &str, rate: f64, now: Instant) -> Option<Duration> {
+        if let Some(until) = self.paused_until.filter(|until| *until > now) {
+            return Some(until - now);
+        }
+
+        let capacity = rate.max(1.0);
+
+        let bucket = self.buckets
+            .entry(host.to_string())
+            .or_insert(Bucket { tokens: capacity, updated: now });
+
+        bucket.tokens = (
+            bucket.tokens +
+            now.duration_since(bucket.updated).as_secs_f64() * rate
+        ).min(capacity);
+        bucket.updated = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            None
+        } else {
+            Some(Duration::from_secs_f64((1.0 - bucket.tokens) / rate))
+        }
+    }
+
+    /// Trips the circuit breaker when too many requests were rejected in a short time
+    fn reject(&mut self) {
+        let now = Instant::now();
+
+        self.rejections.push_back(now);
+
+        while self.rejections.front().is_some_and(|t| now.duration_since(*t) > BURST_WINDOW) {
+            self.rejections.pop_front();
+        }
+
+        if self.rejections.len() >= BURST_SIZE {
+            self.rejections.clear();
+            self.paused_until = Some(now + ARGS.rate_limit_backoff);
+        }
+    }
+}
+
+/// Waits until a request to `host` is allowed by `rate` and the circuit breaker
+async fn acquire(host: &str, rate: Option<f64>) {
+    loop {
+        let wait = {
+            let mut limiter = LIMITER.lock().expect("lock rate limiter");
+
+            match rate {
+                Some(rate) => limiter.take(host, rate),
+                None =>
+                    limiter.paused_until
+                        .filter(|until| *until > Instant::now())
+                        .map(|until| until - Instant::now()),
+            }
+        };
+
+        match wait {
+            Some(wait) => sleep(wait).await,
+            None => {
+                return;
+            }
+        }
+    }
+}
+
+/// Paces requests to an API (by its base URL) when `--rate-limit` is not given; with it,
+/// `send` takes care of that
+pub async fn pace_api(api: &str) {
+    if ARGS.rate_limit.is_none() {
+        acquire(api, Some(DEFAULT_API_RATE)).await;
+    }
+}
+
+/// Sends a request once the rate limiter allows it, and reports rejections to the
+/// circuit breaker, which pauses all requests after a burst of 403/429 responses
+pub async fn send(request: RequestBuilder) -> reqwest::Result<Response> {
+    let (client, request) = request.build_split();
+    let request = request?;
+
+    acquire(request.url().host_str().unwrap_or_default(), ARGS.rate_limit).await;
+
+    let response = client.execute(request).await?;
+
+    let status = response.status();
+
+    if status == StatusCode::FORBIDDEN || status == StatusCode::TOO_MANY_REQUESTS {
+        LIMITER.lock().expect("lock rate limiter").reject();
+    }
+
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn allows_a_burst_of_one_second() {
+        let mut limiter = Limiter::default();
+        let start = Instant::now();
+
+        for _ in 0..4 {
+            assert_eq!(limiter.take_at("a", 4.0, start), None);
+        }
+
+        assert_eq!(limiter.take_at("a", 4.0, start), Some(Duration::from_millis(250)));
+
+        // other hosts have their own bucket
+        assert_eq!(limiter.take_at("b", 4.0, start), None);
+    }
+
+    #[test]
+    fn refills_up_to_the_burst_cap() {
+        let mut limiter = Limiter::default();
+        let start = Instant::now();
+
+        for _ in 0..2 {
+            assert_eq!(limiter.take_at("a", 2.0, start), None);
+        }
+
+        assert_eq!(limiter.take_at("a", 2.0, start + Duration::from_millis(500)), None);
+        assert!(limiter.take_at("a", 2.0, start + Duration::from_millis(500)).is_some());
+
+        // a long pause refills the bucket to its capacity only
+        let later = start + Duration::from_secs(60);
+
+        for _ in 0..2 {
+            assert_eq!(limiter.take_at("a", 2.0, later), None);
+        }
+
+        assert!(limiter.take_at("a", 2.0, later).is_some());
+    }
+
+    #[test]
+    fn slow_rates_allow_one_request_at_a_time() {
+        let mut limiter = Limiter::default();
+        let start = Instant::now();
+
+        assert_eq!(limiter.take_at("a", 0.5, start), None);
+        assert_eq!(limiter.take_at("a", 0.5, start), Some(Duration::from_secs(2)));
+        assert_eq!(limiter.take_at("a", 0.5, start + Duration::from_secs(2)), None);
+    }
+}
@@ -1,168 +1,454 @@
-use crate::{ cli::ARGS, profile::Profile, progress::DownloadAction, target::Target };
-use anyhow::Result;
-use futures::future::join_all;
-use std::{ path::PathBuf, process::exit, sync::Arc, thread };
-use tokio::{ fs, sync::{ Semaphore, mpsc }, task, time::{ Duration, sleep } };
+use crate::{
+    cli::{ ARGS, Command },
+    file::PostFile,
+    journal::Pending,
+    output::{ Event, FileRef },
+    profile::Profile,
+    progress::DownloadAction,
+    retry::Retries,
+    target::Target,
+};
+use anyhow::{ Context, Result, bail };
+use futures::{ FutureExt, StreamExt, future::join_all, stream };
+use std::{ collections::HashSet, path::PathBuf, process::exit, sync::Arc };
+use tokio::{ fs, sync::{ Semaphore, mpsc }, task };
 
 mod api;
+mod bandwidth;
 mod cli;
+mod config;
+mod dry_run;
+mod export;
 mod ext;
+mod favorites;
 mod file;
+mod filter;
 mod http;
+mod journal;
+mod limiter;
+mod metadata;
+mod mirror;
+mod output;
 mod pretty;
 mod profile;
 mod progress;
+mod proxy;
+mod retry;
+mod sync;
 mod target;
+mod template;
 
-#[allow(clippy::too_many_lines)]
 #[tokio::main]
 async fn main() -> Result<()> {
     if ARGS.show_config {
         eprintln!("{}", *ARGS);
     }
 
-    if ARGS.download_archive {
+    proxy::init();
+
+    if (ARGS.download_archive || ARGS.since_last_run || ARGS.command.is_some()) && !ARGS.dry_run {
         fs::create_dir_all(PathBuf::from_iter([&ARGS.output_path, "db"])).await?;
     }
 
-    let targets = Target::from_args().await;
+    let targets = match &ARGS.command {
+        Some(Command::Sync(sync_args)) => {
+            if sync::manage(sync_args)? {
+                return Ok(());
+            }
 
-    let (total_targets, mut last_target) = (targets.len(), false);
+            sync::targets().await
+        }
+        // the journal is continued rather than started anew
+        Some(Command::Resume) => journal::resume().await?,
+        None =>
+            Target::from_args()
+                .await
+                .into_iter()
+                .map(|target| (target, None))
+                .collect(),
+    };
+
+    if
+        !ARGS.list_extensions &&
+        !ARGS.dry_run &&
+        !matches!(ARGS.command, Some(Command::Resume))
+    {
+        journal::start(&targets)?;
+    }
 
-    for (i, target) in targets.into_iter().enumerate() {
-        let mut files = Profile::new(&target, i + 1).await?.files;
+    let total_targets = targets.len();
 
-        if files.is_empty() {
-            if !last_target {
-                eprintln!();
-            }
-            continue;
+    // download slots are shared by all targets
+    let sem = Arc::new(Semaphore::new(ARGS.threads()));
+
+    let mut targets = stream
+        ::iter(targets.into_iter().enumerate())
+        .map(|(i, (target, updated))| {
+            let name = target.to_string();
+
+            process(i, total_targets, target, updated, sem.clone()).map(move |result|
+                result.with_context(|| name)
+            )
+        })
+        .buffer_unordered(ARGS.parallel_targets());
+
+    let mut failed_targets = 0;
+
+    while let Some(result) = targets.next().await {
+        // entries are buffered, a finished or failed target writes them out
+        journal::flush();
+
+        // a failed target must not cancel the downloads of the others
+        if let Err(err) = result {
+            progress::suspend(|| eprintln!("{err:#}"));
+            failed_targets += 1;
         }
+    }
 
-        if ARGS.list_extensions {
-            ext::list(files, &target);
+    proxy::print_stats();
 
-            if i != total_targets - 1 {
-                eprintln!();
-            }
-        } else {
-            if i == total_targets - 1 {
-                last_target = true;
-            }
+    journal::finish(failed_targets > 0 || progress::downloads_failed())?;
 
-            let mut total = files.len();
+    if failed_targets > 0 {
+        bail!("{} failed", pretty::anything(failed_targets, "target", "targets"));
+    }
 
-            if let Some(exts) = ARGS.included() {
-                files.retain(|file| {
-                    file.to_extension(&target).is_some() &&
-                        exts.contains(&file.to_extension(&target).unwrap().to_lowercase())
-                });
+    if progress::downloads_failed() {
+        exit(1);
+    }
 
-                files_left_msg("inclusive filter", total, files.len());
-            } else if let Some(exts) = ARGS.excluded() {
-                files.retain(|file| {
-                    file.to_extension(&target).is_none() ||
-                        !exts.contains(&file.to_extension(&target).unwrap().to_lowercase())
-                });
+    Ok(())
+}
 
-                files_left_msg("exclusive filter", total, files.len());
-            }
+async fn process(
+    i: usize,
+    total_targets: usize,
+    target: Target,
+    updated: Option<String>,
+    sem: Arc<Semaphore>
+) -> Result<()> {
+    let last_target = i == total_targets - 1;
+
+    // listing extensions and dry runs need all files up front
+    let stream = !ARGS.list_extensions && !ARGS.dry_run;
+
+    let (files, newest, failed) = if let Some(pending) = journal::take_pending(i + 1) {
+        let newest = pending.newest.clone();
+
+        (HashSet::new(), newest, resume(&target, i + 1, pending, last_target, sem).await?)
+    } else if stream {
+        let (file_tx, file_rx) = mpsc::channel::<PostFile>(ARGS.threads());
+
+        // if indexing fails, its sender is dropped and the files found so far are still
+        // downloaded, so no download task is left running when the error is returned
+        let (profile, failed) = tokio::join!(
+            index(&target, i + 1, Some(file_tx)),
+            download(&target, i + 1, file_rx, last_target, sem)
+        );
+
+        let (profile, failed) = (profile?, failed?);
+
+        if let Some(format) = ARGS.export && !profile.records.is_empty() {
+            let exported = export::write(&target, profile.records, format).await?;
+            progress::suspend(|| eprintln!("export: saved {} and an index", pretty::posts(exported)));
+        }
 
-            if files.is_empty() {
-                if !last_target {
-                    eprintln!();
-                }
-                continue;
-            }
+        (profile.files, profile.newest, failed)
+    } else {
+        let profile = index(&target, i + 1, None).await?;
+
+        (profile.files, profile.newest, false)
+    };
 
-            if ARGS.download_archive {
-                total = files.len();
+    if !stream {
+        list(&target, files, last_target).await?;
+    }
 
-                let archive = target.archive();
+    if
+        ARGS.since_last_run &&
+        stream &&
+        !failed &&
+        target.is_whole() &&
+        let Some(newest) = newest
+    {
+        filter::write_last_run(&target, &newest).await?;
+    }
 
-                files.retain(|f| {
-                    if let Some(hash) = f.to_hash() { !archive.contains(&hash) } else { true }
-                });
+    if stream && !failed && let Some(updated) = updated {
+        sync::write_updated(&target, &updated)?;
+    }
 
-                let left = files.len();
+    Ok(())
+}
 
-                if total != left {
-                    files_left_msg("download archive", total, left);
-                }
-            }
+/// Lists the extensions or the files of a target instead of downloading them
+async fn list(target: &Target, mut files: HashSet<PostFile>, last_target: bool) -> Result<()> {
+    if files.is_empty() {
+        if !last_target {
+            progress::suspend(|| eprintln!());
+        }
+        return Ok(());
+    }
 
-            if files.is_empty() {
-                if !last_target {
-                    eprintln!();
-                }
-                continue;
+    if ARGS.list_extensions {
+        progress::suspend(|| ext::list(files));
+
+        if !last_target {
+            progress::suspend(|| eprintln!());
+        }
+        return Ok(());
+    }
+
+    let mut total = files.len();
+
+    if let Some(exts) = target.exts() {
+        files.retain(|file| exts.matches(file));
+
+        files_left_msg(exts.name(), total, files.len());
+    }
+
+    if ARGS.download_archive {
+        total = files.len();
+
+        let archive = target.archive();
+
+        files.retain(|f| {
+            if let Some(hash) = f.to_hash() { !archive.contains(&hash) } else { true }
+        });
+
+        let left = files.len();
+
+        if total != left {
+            files_left_msg("download archive", total, left);
+        }
+    }
+
+    if !files.is_empty() {
+        dry_run::list(target, files).await?;
+    }
+
+    if !last_target {
+        progress::suspend(|| eprintln!());
+    }
+
+    Ok(())
+}
+
+/// Indexes a target and saves its posts, while its files may already be downloading
+async fn index(
+    target: &Target,
+    id: usize,
+    queue: Option<mpsc::Sender<PostFile>>
+) -> Result<Profile> {
+    let profile = Profile::new(target, id, queue).await?;
+
+    output::emit(
+        &(Event::Target {
+            id,
+            target: target.to_string(),
+            posts: profile.post_count,
+            files: profile.files.len(),
+        })
+    );
+
+    if !ARGS.list_extensions && !ARGS.dry_run && ARGS.write_metadata && !profile.records.is_empty() {
+        let written = metadata::write(target, &profile.records).await?;
+        progress::suspend(|| eprintln!("metadata: saved {}", pretty::posts(written)));
+    }
+
+    if !ARGS.list_extensions && !ARGS.dry_run {
+        for (section, texts) in &profile.texts {
+            metadata::write_texts(target, *section, texts).await?;
+            progress::suspend(|| eprintln!("{section}: saved {}", pretty::posts(texts.len())));
+        }
+    }
+
+    journal::indexed(id, profile.post_count, profile.files.len(), profile.newest.as_deref());
+
+    Ok(profile)
+}
+
+/// Downloads the files left over from the last run, without indexing the target again
+async fn resume(
+    target: &Target,
+    id: usize,
+    pending: Pending,
+    last_target: bool,
+    sem: Arc<Semaphore>
+) -> Result<bool> {
+    progress::suspend(||
+        eprintln!(
+            "#{}: {target} resumed with {} of {} left",
+            pretty::n_fmt(id as u64),
+            pretty::n_fmt(pending.files.len() as u64),
+            pretty::files(pending.total)
+        )
+    );
+
+    output::emit(
+        &(Event::Target {
+            id,
+            target: target.to_string(),
+            posts: pending.posts,
+            files: pending.total,
+        })
+    );
+
+    let (file_tx, file_rx) = mpsc::channel::<PostFile>(ARGS.threads());
+
+    let queue = async move {
+        for file in pending.files {
+            if file_tx.send(file).await.is_err() {
+                break;
             }
+        }
+    };
+
+    let ((), failed) = tokio::join!(queue, download(target, id, file_rx, last_target, sem));
+
+    failed
+}
+
+/// Downloads the files of a target as they are found, returns whether any download failed
+async fn download(
+    target: &Target,
+    id: usize,
+    mut file_rx: mpsc::Receiver<PostFile>,
+    last_target: bool,
+    sem: Arc<Semaphore>
+) -> Result<bool> {
+    let exts = target.exts();
+
+    let (mut total, mut matching, mut left) = (0, 0, 0);
 
-            let left = files.len();
+    let mut progress = None;
 
+    let mut tasks = Vec::new();
+
+    while let Some(file) = file_rx.recv().await {
+        total += 1;
+
+        if exts.as_ref().is_some_and(|exts| !exts.matches(&file)) {
+            continue;
+        }
+
+        matching += 1;
+
+        if
+            ARGS.download_archive &&
+            file.to_hash().is_some_and(|hash| target.archive().contains(&hash))
+        {
+            continue;
+        }
+
+        left += 1;
+
+        // the bar is only shown once there is something to download
+        let msg_tx = if let Some((msg_tx, _)) = &progress {
+            msg_tx
+        } else {
             fs::create_dir_all(target.to_pathbuf(None)).await?;
 
             let archive_path = target.to_archive_pathbuf();
 
-            let (msg_tx, msg_rx) = mpsc::channel::<DownloadAction>(left);
+            let label = if ARGS.parallel_targets() > 1 {
+                format!("{target}: ")
+            } else {
+                String::new()
+            };
+
+            let (msg_tx, msg_rx) = mpsc::channel::<DownloadAction>(ARGS.threads());
+
+            let bar = task::spawn_blocking(move ||
+                progress::bar(archive_path, msg_rx, label, last_target)
+            );
 
-            thread::spawn(move || progress::bar(left as u64, archive_path, msg_rx, last_target));
+            &progress.insert((msg_tx, bar)).0
+        };
 
-            let mut tasks = Vec::new();
+        msg_tx.send(DownloadAction::Queue).await.expect("send state to progress bar");
 
-            let sem = Arc::new(Semaphore::new(ARGS.threads()));
+        output::emit(&(Event::Queued { file: FileRef::new(&file, target), size: None }));
 
-            for file in files {
-                let permit = sem.clone().acquire_owned().await;
+        let permit = sem.clone().acquire_owned().await;
 
-                let msg_tx = msg_tx.clone();
+        let msg_tx = msg_tx.clone();
 
-                let target = target.clone();
+        let target = target.clone();
 
-                tasks.push(
-                    task::spawn(async move {
-                        #[allow(clippy::no_effect_underscore_binding)]
-                        let _permit = permit;
+        tasks.push(
+            task::spawn(async move {
+                #[allow(clippy::no_effect_underscore_binding)]
+                let _permit = permit;
 
-                        let result = file.download(&target, msg_tx.clone()).await;
+                let mut retries = Retries::default();
 
-                        match result {
-                            Ok(action) => {
-                                msg_tx.send(action).await.expect("send state to progress bar");
-                            }
-                            Err(err) => {
-                                let mut error = err.to_string();
-                                if let Some(source) = err.source() {
-                                    error.push('\n');
-                                    error.push_str(&source.to_string());
-                                }
-                                msg_tx
-                                    .send(DownloadAction::Fail(error)).await
-                                    .expect("send state to progress bar");
-                            }
+                let action = match file.download(&target, msg_tx.clone(), &mut retries).await {
+                    Ok(action) => action,
+                    Err(err) => {
+                        let mut error = err.to_string();
+                        if let Some(source) = err.source() {
+                            error.push('\n');
+                            error.push_str(&source.to_string());
                         }
-                    })
-                );
-            }
+                        DownloadAction::Fail(error)
+                    }
+                };
+
+                output::report(&file, &target, &action, retries.total()).await;
+
+                if
+                    let
+                    | DownloadAction::Complete(_)
+                    | DownloadAction::Skip(_)
+                    | DownloadAction::SkipFiltered(_) = action
+                {
+                    journal::done(id, &file);
+                }
 
-            join_all(tasks).await;
+                if retries.total() > 0 {
+                    msg_tx
+                        .send(
+                            DownloadAction::ReportRetries(file.to_output_name(), retries.total())
+                        ).await
+                        .expect("send state to progress bar");
+                }
 
-            // wait so the bar can finish properly
-            sleep(Duration::from_millis((left / 10).try_into().unwrap_or_default())).await;
-        }
+                msg_tx.send(action).await.expect("send state to progress bar");
+            })
+        );
     }
 
-    if progress::downloads_failed() {
-        exit(1);
+    // indexing is done, so the filter results are final
+    if let Some(exts) = &exts {
+        files_left_msg(exts.name(), total, matching);
     }
 
-    Ok(())
+    if matching != left {
+        files_left_msg("download archive", matching, left);
+    }
+
+    let Some((msg_tx, bar)) = progress else {
+        if !last_target {
+            progress::suspend(|| eprintln!());
+        }
+        return Ok(false);
+    };
+
+    join_all(tasks).await;
+
+    drop(msg_tx);
+
+    // wait so the bar can finish properly
+    Ok(bar.await.is_ok_and(|result| result.unwrap_or_default()))
 }
 
 fn files_left_msg(filter: &str, total: usize, left: usize) {
-    eprintln!(
-        "{filter}: skipping {}, {} left to download/check",
-        pretty::files(total - left),
-        pretty::files(left)
+    progress::suspend(||
+        eprintln!(
+            "{filter}: skipping {}, {} left to download/check",
+            pretty::files(total - left),
+            pretty::files(left)
+        )
     );
 }
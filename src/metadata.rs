@@ -0,0 +1,66 @@
+use crate::{ api::PostInfo, cli::Extra, file::PostFile, target::Target, template };
+use anyhow::{ Context, Result };
+use serde_json::Value;
+use std::path::PathBuf;
+use tokio::fs;
+
+/// A post as returned by the API, along with its (deduplicated) files
+pub struct PostRecord {
+    pub info: PostInfo,
+    pub json: Value,
+    pub files: Vec<PostFile>,
+}
+
+/// Sidecar path for a post, next to the post's files
+pub fn to_pathbuf(target: &Target, post: &PostInfo) -> PathBuf {
+    to_post_pathbuf(target, post, "json")
+}
+
+/// Path of a per-post file with the given extension, next to the post's files
+pub fn to_post_pathbuf(target: &Target, post: &PostInfo, extension: &str) -> PathBuf {
+    let name = format!("post_{}.{extension}", template::sanitize(&post.id));
+
+    match post.to_subdir() {
+        Some(dir) => target.to_pathbuf(Some(&dir)).join(name),
+        None => target.to_pathbuf(Some(&name)),
+    }
+}
+
+/// Writes one JSON sidecar per post, returns the number of files written
+pub async fn write(target: &Target, records: &[PostRecord]) -> Result<usize> {
+    let mut written = 0;
+
+    for PostRecord { info, json, .. } in records {
+        let path = to_pathbuf(target, info);
+
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir).await.with_context(||
+                format!("create metadata directory: {}", dir.display())
+            )?;
+        }
+
+        fs::write(&path, serde_json::to_vec_pretty(json)?).await.with_context(||
+            format!("write metadata: {}", path.display())
+        )?;
+
+        written += 1;
+    }
+
+    Ok(written)
+}
+
+/// Writes the API data of a creator section (DMs, announcements, ...) as a whole, as
+/// `{section}/{section}.json`, since most of these entries only consist of text
+pub async fn write_texts(target: &Target, section: Extra, texts: &[Value]) -> Result<()> {
+    let dir = target.to_pathbuf(Some(&section.to_string()));
+
+    fs::create_dir_all(&dir).await.with_context(||
+        format!("create {section} directory: {}", dir.display())
+    )?;
+
+    let path = dir.join(format!("{section}.json"));
+
+    fs::write(&path, serde_json::to_vec_pretty(texts)?).await.with_context(||
+        format!("write {section}: {}", path.display())
+    )
+}
@@ -0,0 +1,154 @@
+use crate::target::Site;
+use std::{ error::Error, io, sync::atomic::{ AtomicUsize, Ordering::Relaxed } };
+
+/// Consecutive connection, TLS or timeout errors after which the next domain of a site is used
+const FAILOVER_AFTER: usize = 3;
+
+/// Index of the domain in use and consecutive connection errors, per site
+struct Mirror {
+    current: AtomicUsize,
+    failures: AtomicUsize,
+}
+
+impl Mirror {
+    const fn new() -> Self {
+        Self { current: AtomicUsize::new(0), failures: AtomicUsize::new(0) }
+    }
+
+    fn succeed(&self) {
+        self.failures.store(0, Relaxed);
+    }
+
+    /// Counts a failed request to the `observed` domain out of `mirrors`, returns whether
+    /// this switched to the next domain
+    fn fail(&self, observed: usize, mirrors: usize) -> bool {
+        if
+            mirrors < 2 ||
+            self.current.load(Relaxed) != observed ||
+            self.failures.fetch_add(1, Relaxed) + 1 < FAILOVER_AFTER
+        {
+            return false;
+        }
+
+        let next = (observed + 1) % mirrors;
+
+        if self.current.compare_exchange(observed, next, Relaxed, Relaxed).is_err() {
+            return false;
+        }
+
+        self.failures.store(0, Relaxed);
+
+        true
+    }
+}
+
+static COOMER: Mirror = Mirror::new();
+static KEMONO: Mirror = Mirror::new();
+
+fn of(site: Site) -> &'static Mirror {
+    match site {
+        Site::Coomer => &COOMER,
+        Site::Kemono => &KEMONO,
+    }
+}
+
+/// Index of the domain in use, which is kept for the rest of the run
+pub fn current(site: Site) -> usize {
+    of(site).current.load(Relaxed)
+}
+
+pub fn succeed(site: Site) {
+    of(site).succeed();
+}
+
+/// Switches to the next domain (in order, starting over after the last one) once the
+/// current one could not be reached repeatedly.
+///
+/// `observed` is the domain the request was sent to; failed requests to a domain that
+/// was already switched away from are ignored, so that the site only switches once.
+pub fn fail(site: Site, observed: usize) {
+    let from = site.host();
+
+    if of(site).fail(observed, site.mirrors()) {
+        eprintln!("{from} is unreachable, switching to {}", site.host());
+    }
+}
+
+/// Errors that suggest the domain can't be reached: connection errors, timeouts and
+/// TLS errors (which surface as invalid data read from the connection)
+pub fn is_unreachable(err: &reqwest::Error) -> bool {
+    if err.is_connect() || err.is_timeout() {
+        return true;
+    }
+
+    let mut source = err.source();
+
+    while let Some(err) = source {
+        if
+            err
+                .downcast_ref::<io::Error>()
+                .is_some_and(|err| err.kind() == io::ErrorKind::InvalidData)
+        {
+            return true;
+        }
+
+        source = err.source();
+    }
+
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Fails `count` requests to the domain in use, returns the domains switched to
+    fn fail_streak(mirror: &Mirror, count: usize, mirrors: usize) -> Vec<usize> {
+        let observed = mirror.current.load(Relaxed);
+
+        (0..count)
+            .filter(|_| mirror.fail(observed, mirrors))
+            .map(|_| mirror.current.load(Relaxed))
+            .collect()
+    }
+
+    #[test]
+    fn switches_once_per_failure_streak() {
+        let mirror = Mirror::new();
+
+        assert!(fail_streak(&mirror, FAILOVER_AFTER - 1, 3).is_empty());
+        assert_eq!(fail_streak(&mirror, 1, 3), [1]);
+
+        // requests that were still sent to the old domain don't switch again
+        assert_eq!((0..10).filter(|_| mirror.fail(0, 3)).count(), 0);
+        assert_eq!(mirror.current.load(Relaxed), 1);
+
+        assert_eq!(fail_streak(&mirror, FAILOVER_AFTER * 2, 3), [2]);
+    }
+
+    #[test]
+    fn wraps_around_after_the_last_domain() {
+        let mirror = Mirror::new();
+
+        let switched: Vec<usize> = (0..3).flat_map(|_| fail_streak(&mirror, FAILOVER_AFTER, 3)).collect();
+
+        assert_eq!(switched, [1, 2, 0]);
+    }
+
+    #[test]
+    fn successes_reset_the_streak() {
+        let mirror = Mirror::new();
+
+        assert!(fail_streak(&mirror, FAILOVER_AFTER - 1, 2).is_empty());
+        mirror.succeed();
+        assert!(fail_streak(&mirror, FAILOVER_AFTER - 1, 2).is_empty());
+        assert_eq!(mirror.current.load(Relaxed), 0);
+    }
+
+    #[test]
+    fn keeps_a_single_domain() {
+        let mirror = Mirror::new();
+
+        assert!(fail_streak(&mirror, FAILOVER_AFTER * 2, 1).is_empty());
+    }
+}
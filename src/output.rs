@@ -0,0 +1,108 @@
+use crate::{
+    cli::{ ARGS, OutputFormat },
+    file::PostFile,
+    progress::DownloadAction,
+    target::Target,
+};
+use serde::Serialize;
+use std::io::{ self, Write };
+use tokio::fs;
+
+/// Identifies a file in JSON events
+#[derive(Debug, Clone, Serialize)]
+pub struct FileRef {
+    target: String,
+    url: String,
+    path: String,
+}
+
+impl FileRef {
+    pub fn new(file: &PostFile, target: &Target) -> Self {
+        Self {
+            target: target.to_string(),
+            url: file.to_url(target),
+            path: file.to_pathbuf(target).display().to_string(),
+        }
+    }
+}
+
+/// Events printed as JSON Lines with `--output-format json`
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    Target {
+        id: usize,
+        target: String,
+        posts: usize,
+        files: usize,
+    },
+    Queued {
+        #[serde(flatten)]
+        file: FileRef,
+        size: Option<u64>,
+    },
+    Complete {
+        #[serde(flatten)]
+        file: FileRef,
+        bytes: Option<u64>,
+        hash: Option<String>,
+        retries: usize,
+    },
+    Skip {
+        #[serde(flatten)]
+        file: FileRef,
+        reason: String,
+        hash: Option<String>,
+    },
+    Fail {
+        #[serde(flatten)]
+        file: FileRef,
+        error: String,
+        retries: usize,
+    },
+}
+
+pub fn is_json() -> bool {
+    ARGS.output_format == OutputFormat::Json
+}
+
+pub fn emit(event: &Event) {
+    if !is_json() {
+        return;
+    }
+
+    let line = serde_json::to_string(event).expect("serialize event");
+
+    // a closed stdout must not abort downloads
+    let _ = writeln!(io::stdout().lock(), "{line}");
+}
+
+/// Emits the final outcome of a download, along with the number of retries it needed
+pub async fn report(file: &PostFile, target: &Target, action: &DownloadAction, retries: usize) {
+    if !is_json() {
+        return;
+    }
+
+    let file_ref = FileRef::new(file, target);
+
+    let event = match action {
+        DownloadAction::Complete(hash) =>
+            Event::Complete {
+                bytes: fs::metadata(file.to_pathbuf(target)).await.ok().map(|m| m.len()),
+                file: file_ref,
+                hash: hash.clone(),
+                retries,
+            },
+        DownloadAction::Skip(hash) =>
+            Event::Skip { file: file_ref, reason: "exists".to_string(), hash: hash.clone() },
+        DownloadAction::SkipFiltered(reason) =>
+            Event::Skip { file: file_ref, reason: reason.to_string(), hash: None },
+        DownloadAction::Fail(error) =>
+            Event::Fail { file: file_ref, error: error.clone(), retries },
+        _ => {
+            return;
+        }
+    };
+
+    emit(&event);
+}
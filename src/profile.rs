@@ -1,21 +1,33 @@
 use crate::{
-    api::{ self, DiscordChannel, DiscordPost, PagePost, Post, SinglePost },
+    api::{ self, DiscordChannel, DiscordPost, ExtraPost, Json, PagePost, Post, SinglePost },
+    cli::{ ARGS, Extra },
     file::PostFile,
-    http::CLIENT,
+    filter::{ self, DateRange },
+    journal,
+    metadata::PostRecord,
     pretty::{ self, n_fmt },
+    progress,
     target::{ SubType, Target },
 };
 use anyhow::Result;
+use futures::{ StreamExt, stream };
 use indicatif::{ ProgressBar, ProgressStyle };
-use std::{ collections::HashSet, fmt, thread };
+use serde_json::Value;
+use std::{ collections::{ BTreeMap, HashSet }, fmt, thread };
 use tokio::{ sync::mpsc, time::{ Duration, sleep } };
 
 pub struct Profile {
     target_id: usize,
     pub target: Target,
-    post_count: usize,
-    posts: Vec<Box<dyn Post>>,
+    pub post_count: usize,
     pub files: HashSet<PostFile>,
+    /// Receives every new file as soon as its page was retrieved
+    queue: Option<mpsc::Sender<PostFile>>,
+    pub records: Vec<PostRecord>,
+    pub texts: BTreeMap<Extra, Vec<Value>>,
+    range: DateRange,
+    skipped_posts: usize,
+    pub newest: Option<String>,
 }
 
 impl fmt::Display for Profile {
@@ -55,7 +67,7 @@ impl fmt::Display for Profile {
 }
 
 fn page_progress(mut msg_rx: mpsc::UnboundedReceiver<String>) {
-    let bar = ProgressBar::new_spinner();
+    let bar = progress::add(ProgressBar::new_spinner());
 
     bar.set_style(ProgressStyle::with_template("[{elapsed_precise}] {msg}").unwrap());
 
@@ -68,14 +80,56 @@ fn page_progress(mut msg_rx: mpsc::UnboundedReceiver<String>) {
     bar.finish();
 }
 
+/// Retrieves a page of a creator's posts, retrying on errors
+async fn page(
+    target: &Target,
+    user: &str,
+    offset: usize,
+    msg_tx: &mpsc::UnboundedSender<String>
+) -> Result<Vec<Json<PagePost>>> {
+    let mut retries = 0;
+
+    loop {
+        let msg = format!(
+            "Retrieving posts for {target} page #{}{}",
+            (offset + 50) / 50,
+            if retries > 0 {
+                format!(" (Retry #{retries})")
+            } else {
+                String::new()
+            }
+        );
+
+        msg_tx.send(msg)?;
+
+        match api::page(target, user, offset).await {
+            Ok(posts) => return Ok(posts),
+            Err(err) => {
+                err.interpret(retries).await?;
+                retries += 1;
+            }
+        }
+    }
+}
+
 impl Profile {
-    pub async fn new(target: &Target, target_id: usize) -> Result<Self> {
+    /// Indexes a target, sending its files to `queue` (if any) while pages are retrieved
+    pub async fn new(
+        target: &Target,
+        target_id: usize,
+        queue: Option<mpsc::Sender<PostFile>>
+    ) -> Result<Self> {
         let mut profile = Self {
             target_id,
             target: target.clone(),
             post_count: 0,
-            posts: Vec::new(),
             files: HashSet::new(),
+            queue,
+            records: Vec::new(),
+            texts: BTreeMap::new(),
+            range: DateRange::new(target).await?,
+            skipped_posts: 0,
+            newest: None,
         };
 
         match target {
@@ -89,81 +143,142 @@ impl Profile {
         // wait for progress bar to finish
         sleep(Duration::from_millis(1)).await;
 
-        profile.init_files();
+        progress::suspend(|| {
+            if profile.skipped_posts > 0 {
+                eprintln!("post filter: skipping {}", pretty::posts(profile.skipped_posts));
+            }
 
-        eprintln!("{profile}");
+            eprintln!("{profile}");
+        });
 
-        // discard posts
-        profile.posts.clear();
+        // no more files, downloads can finish once the queue is empty
+        profile.queue = None;
 
         Ok(profile)
     }
 
     async fn init_posts_standard(&mut self, user: &str, subtype: &SubType) -> Result<()> {
         if let SubType::Post(post) = subtype {
-            let post: SinglePost = CLIENT.get(
-                format!(
-                    "https://{}/api/v1/{}/user/{user}/post/{post}",
-                    self.target.as_service().site(),
-                    self.target.as_service()
-                )
-            )
-                .send().await?
+            let service = self.target.as_service();
+
+            let post: SinglePost = api
+                ::get(service.to_site(), &format!("/{service}/user/{user}/post/{post}")).await?
                 .json().await?;
 
-            self.posts.push(Box::new(post));
+            self.add_files(post).await;
         } else {
             let (msg_tx, msg_rx) = mpsc::unbounded_channel::<String>();
 
             thread::spawn(move || page_progress(msg_rx));
 
-            let mut offset = if let SubType::PageOffset(o) = subtype { *o } else { 0 };
-
-            loop {
-                let mut retries = 0;
-
-                let posts: Vec<PagePost>;
-
-                loop {
-                    let msg = format!(
-                        "Retrieving posts for {} page #{}{}",
-                        self.target,
-                        (offset + 50) / 50,
-                        if retries > 0 {
-                            format!(" (Retry #{retries})")
-                        } else {
-                            String::new()
-                        }
-                    );
+            if let SubType::Section(section) = subtype {
+                return self.init_posts_extra(user, *section, &msg_tx).await;
+            }
 
-                    msg_tx.send(msg)?;
+            let mut offset = if let SubType::PageOffset(o) = subtype { *o } else { 0 };
 
-                    match api::page(&self.target, user, offset).await {
-                        Ok(p) => {
-                            posts = p;
-                            break;
-                        }
-                        Err(err) => {
-                            err.interpret(retries).await?;
-                            retries += 1;
-                        }
+            let mut done = false;
+
+            // pages covering the known post count are retrieved concurrently, but added in
+            // order; not with a start date, as older pages would be retrieved in vain
+            if
+                let SubType::None = subtype &&
+                ARGS.page_concurrency() > 1 &&
+                !self.range.skips_older() &&
+                let Some(total) = self.post_count().await &&
+                total > 50
+            {
+                let target = self.target.clone();
+
+                let mut pages = stream
+                    ::iter((0..total).step_by(50))
+                    .map(|offset| page(&target, user, offset, &msg_tx))
+                    .buffered(ARGS.page_concurrency());
+
+                while let Some(posts) = pages.next().await {
+                    let posts = posts?;
+
+                    if posts.is_empty() || self.add_posts(posts).await {
+                        done = true;
+                        break;
                     }
+
+                    offset += 50;
                 }
+            }
+
+            // remaining pages one at a time; these only hold older posts that were pushed
+            // past the known count, posts added since the profile was retrieved are at
+            // offset 0 and left to the next run
+            while !done {
+                let posts = page(&self.target, user, offset, &msg_tx).await?;
 
                 if posts.is_empty() {
                     break;
                 }
 
-                for post in posts {
-                    self.posts.push(Box::new(post));
+                done = self.add_posts(posts).await || matches!(subtype, SubType::PageOffset(_));
+
+                offset += 50;
+            }
+
+            if let SubType::None = subtype {
+                for section in &ARGS.extras {
+                    self.init_posts_extra(user, *section, &msg_tx).await?;
                 }
+            }
+        }
 
-                if let SubType::PageOffset(_) = subtype {
-                    break;
+        Ok(())
+    }
+
+    async fn init_posts_extra(
+        &mut self,
+        user: &str,
+        section: Extra,
+        msg_tx: &mpsc::UnboundedSender<String>
+    ) -> Result<()> {
+        let mut offset = 0;
+
+        loop {
+            let mut retries = 0;
+
+            let posts: Vec<Json<ExtraPost>>;
+
+            loop {
+                let msg = format!(
+                    "Retrieving {section} for {} page #{}{}",
+                    self.target,
+                    (offset + 50) / 50,
+                    if retries > 0 {
+                        format!(" (Retry #{retries})")
+                    } else {
+                        String::new()
+                    }
+                );
+
+                msg_tx.send(msg)?;
+
+                match api::extra(&self.target, user, section, offset).await {
+                    Ok(p) => {
+                        posts = p;
+                        break;
+                    }
+                    Err(err) => {
+                        err.interpret(retries).await?;
+                        retries += 1;
+                    }
                 }
+            }
 
-                offset += 50;
+            let count = posts.len();
+
+            // unpaged sections are returned at once, a short page is the last one
+            if self.add_posts(posts).await || !section.is_paged() || count < 50 {
+                break;
             }
+
+            offset += 50;
         }
 
         Ok(())
@@ -193,7 +308,7 @@ impl Profile {
             loop {
                 let mut retries = 0;
 
-                let posts: Vec<DiscordPost>;
+                let posts: Vec<Json<DiscordPost>>;
 
                 loop {
                     let msg = format!(
@@ -221,14 +336,10 @@ impl Profile {
                     }
                 }
 
-                if posts.is_empty() {
+                if posts.is_empty() || self.add_posts(posts).await {
                     break;
                 }
 
-                for post in posts {
-                    self.posts.push(Box::new(post));
-                }
-
                 offset += 150;
             }
         }
@@ -236,15 +347,99 @@ impl Profile {
         Ok(())
     }
 
-    fn init_files(&mut self) {
-        self.post_count = self.posts.len();
+    /// Post count from the creator's profile; pages are retrieved one at a time without it
+    async fn post_count(&self) -> Option<usize> {
+        match self.target.post_count().await {
+            Ok(count) => count,
+            Err(err) => {
+                progress::suspend(||
+                    eprintln!(
+                        "{}: post count unavailable, retrieving pages one at a time: {err}",
+                        self.target
+                    )
+                );
+                None
+            }
+        }
+    }
+
+    /// Keeps posts matching the post filters, returns whether older pages can be skipped
+    async fn add_posts<P: Post>(&mut self, posts: Vec<P>) -> bool {
+        // sections are retrieved as a whole and don't affect `--since-last-run`
+        let past_range = posts
+            .last()
+            .is_some_and(|post| post.info().section.is_none() && self.range.is_past(post.info()));
+
+        for post in posts {
+            if
+                !self.range.contains(post.info()) ||
+                !filter::matches_text(post.info().title.as_deref(), post.content())
+            {
+                self.skipped_posts += 1;
+                continue;
+            }
 
-        self.posts.drain(..).for_each(|mut post| {
-            post.files()
-                .into_iter()
-                .for_each(|file| {
-                    self.files.insert(file);
-                });
-        });
+            if
+                post.info().section.is_none() &&
+                let Some(date) = post.info().date() &&
+                filter::is_later(date, self.newest.as_deref())
+            {
+                self.newest = Some(date.to_string());
+            }
+
+            if let Some(section) = post.info().section && let Some(raw) = post.raw() {
+                self.texts.entry(section).or_default().push(raw.clone());
+            }
+
+            self.add_files(post).await;
+        }
+
+        past_range
+    }
+
+    /// Collects the files of a post, queueing the ones not seen before
+    async fn add_files(&mut self, mut post: impl Post) {
+        self.post_count += 1;
+
+        let keep_records = ARGS.write_metadata || ARGS.export.is_some();
+
+        let mut files = Vec::new();
+        let mut new_files = Vec::new();
+
+        for file in post.files() {
+            // a file shared by multiple posts keeps the location of its first post
+            if let Some(known) = self.files.get(&file) {
+                if keep_records {
+                    files.push(known.clone());
+                }
+            } else {
+                if keep_records {
+                    files.push(file.clone());
+                }
+
+                new_files.push(file.clone());
+                self.files.insert(file);
+            }
+        }
+
+        if let Some(queue) = &self.queue && !new_files.is_empty() {
+            for file in &new_files {
+                journal::file(self.target_id, file);
+            }
+
+            // a download in progress must be in the journal, or resuming would remove
+            // its temporary file
+            journal::flush();
+
+            for file in new_files {
+                // waits while the queue is full; the receiver is only gone if
+                // downloads were aborted
+                let _ = queue.send(file).await;
+            }
+        }
+
+        if keep_records && let Some(json) = post.json() {
+            self.records.push(PostRecord { info: post.info().clone(), json, files });
+        }
     }
 }
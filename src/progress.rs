@@ -1,47 +1,73 @@
-use crate::{ cli::ARGS, pretty::n_fmt };
+use crate::{ cli::ARGS, pretty::{ self, n_fmt } };
 use anyhow::Result;
-use indicatif::{ HumanBytes, ProgressBar, ProgressStyle };
-use std::{ fmt, fs::File, io::Write, path::PathBuf, process::exit, time::Duration };
+use indicatif::{ HumanBytes, MultiProgress, ProgressBar, ProgressStyle };
+use std::{
+    collections::BTreeMap,
+    fmt,
+    fs::File,
+    io::Write,
+    path::PathBuf,
+    process::exit,
+    sync::{ LazyLock, atomic::{ AtomicBool, Ordering::Relaxed } },
+    time::Duration,
+};
+use strum_macros::Display;
 use tokio::sync::mpsc::Receiver;
 
 #[derive(Clone)]
 pub enum DownloadAction {
+    Queue,
     Start,
     Wait,
     Continue,
     ReportSize(u64),
     ReportLegacyHashSkip(String),
+    ReportRetries(String, usize),
     Skip(Option<String>),
+    SkipFiltered(SkipReason),
     Fail(String),
     Complete(Option<String>),
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Display)]
+pub enum SkipReason {
+    #[strum(to_string = "too small")]
+    TooSmall,
+    #[strum(to_string = "too large")]
+    TooLarge,
+}
+
 struct Stats {
     queued: u64,
     waiting: u64,
     active: u64,
     complete: u64,
     skipped: u64,
+    filtered: BTreeMap<SkipReason, u64>,
     failed: u64,
     dl_size: u64,
     errors: Vec<String>,
+    retried: Vec<(String, usize)>,
     archive: Option<File>,
 }
 
 impl Stats {
-    pub fn new(files: u64, archive_path: &PathBuf) -> Self {
+    pub fn new(archive_path: &PathBuf) -> Self {
         Self {
-            queued: files,
+            queued: 0,
             waiting: 0,
             active: 0,
             complete: 0,
             skipped: 0,
+            filtered: BTreeMap::new(),
             failed: 0,
 
             dl_size: 0,
 
             errors: Vec::new(),
 
+            retried: Vec::new(),
+
             archive: if ARGS.download_archive {
                 Some(Self::open_archive(archive_path))
             } else {
@@ -77,6 +103,10 @@ impl Stats {
     #[allow(clippy::needless_pass_by_value)]
     fn update(&mut self, download_state: DownloadAction) -> bool {
         match download_state {
+            DownloadAction::Queue => {
+                self.queued += 1;
+                false
+            }
             DownloadAction::Start => {
                 self.queued -= 1;
                 self.active += 1;
@@ -103,12 +133,22 @@ impl Stats {
                 self.errors.push(format!("skipped hash verification for legacy file: {name}"));
                 false
             }
+            DownloadAction::ReportRetries(name, retries) => {
+                self.retried.push((name, retries));
+                false
+            }
             DownloadAction::Skip(hash) => {
                 self.active -= 1;
                 self.skipped += 1;
                 self.write_to_archive(hash);
                 true
             }
+            DownloadAction::SkipFiltered(reason) => {
+                self.active -= 1;
+                self.skipped += 1;
+                *self.filtered.entry(reason).or_default() += 1;
+                true
+            }
             DownloadAction::Fail(err) => {
                 self.active -= 1;
                 self.failed += 1;
@@ -128,11 +168,53 @@ impl Stats {
     }
 }
 
+impl Stats {
+    /// Lists the files that needed the most retries
+    fn print_retries(&mut self) {
+        const SHOWN: usize = 10;
+
+        if self.retried.is_empty() {
+            return;
+        }
+
+        self.retried.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+
+        let total: usize = self.retried.iter().map(|(_, retries)| retries).sum();
+
+        eprintln!(
+            "retries: {} needed {}",
+            pretty::files(self.retried.len()),
+            pretty::anything(total, "retry", "retries")
+        );
+
+        for (name, retries) in self.retried.iter().take(SHOWN) {
+            eprintln!("  {name}: {retries}");
+        }
+
+        if self.retried.len() > SHOWN {
+            eprintln!("  ... and {} more", n_fmt((self.retried.len() - SHOWN) as u64));
+        }
+    }
+}
+
 impl fmt::Display for Stats {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let filtered = if self.filtered.is_empty() {
+            String::new()
+        } else {
+            format!(
+                " ({})",
+                self.filtered
+                    .iter()
+                    .map(|(reason, n)| format!("{} {reason}", n_fmt(*n)))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            )
+        };
+
         writeln!(
             f,
-            "downloaded {} / {} queued / {} waiting / {} active / {} complete / {} skipped / {} failed",
+            "downloaded {} / {} queued / {} waiting / {} active / {} complete / {} skipped{filtered} / {} failed",
             HumanBytes(self.dl_size),
             n_fmt(self.queued),
             n_fmt(self.waiting),
@@ -144,20 +226,37 @@ impl fmt::Display for Stats {
     }
 }
 
-static mut DOWNLOADS_FAILED: bool = false;
+static DOWNLOADS_FAILED: AtomicBool = AtomicBool::new(false);
+
+/// Indexing spinners and download bars, shown at the same time while files are streamed
+static MULTI: LazyLock<MultiProgress> = LazyLock::new(MultiProgress::new);
 
 pub fn downloads_failed() -> bool {
-    unsafe { DOWNLOADS_FAILED }
+    DOWNLOADS_FAILED.load(Relaxed)
 }
 
+/// Registers a bar with the shared display, so that bars don't draw over each other
+pub fn add(bar: ProgressBar) -> ProgressBar {
+    MULTI.add(bar)
+}
+
+/// Runs `f` with the shared display hidden, so that printed lines end up above the bars
+pub fn suspend<R>(f: impl FnOnce() -> R) -> R {
+    MULTI.suspend(f)
+}
+
+/// Shows the download progress of a target until all senders are dropped,
+/// returning whether any of its downloads failed.
+///
+/// The bar grows with every queued file, as files arrive while the target is indexed.
 #[allow(clippy::needless_pass_by_value)]
 pub fn bar(
-    files: u64,
     archive: PathBuf,
     mut msg_rx: Receiver<DownloadAction>,
+    label: String,
     last_target: bool
-) -> Result<()> {
-    let bar = ProgressBar::new(files);
+) -> Result<bool> {
+    let bar = add(ProgressBar::new(0));
 
     bar.set_style(
         ProgressStyle::with_template(
@@ -167,9 +266,13 @@ pub fn bar(
 
     bar.enable_steady_tick(Duration::from_millis(200));
 
-    let mut stats = Stats::new(files, &archive);
+    let mut stats = Stats::new(&archive);
 
     while let Some(state) = msg_rx.blocking_recv() {
+        if let DownloadAction::Queue = state {
+            bar.inc_length(1);
+        }
+
         if stats.update(state) {
             bar.inc(1);
         }
@@ -178,20 +281,24 @@ pub fn bar(
             bar.set_message(format!("\n{}", stats.errors.join("\n")));
         }
 
-        bar.set_prefix(stats.to_string());
+        bar.set_prefix(format!("{label}{stats}"));
     }
 
     bar.finish();
 
-    if !last_target {
-        eprintln!("\n");
-    }
+    suspend(|| {
+        stats.print_retries();
 
-    if stats.failed > 0 {
-        unsafe {
-            DOWNLOADS_FAILED = true;
+        if !last_target {
+            eprintln!("\n");
         }
+    });
+
+    let failed = stats.failed > 0;
+
+    if failed {
+        DOWNLOADS_FAILED.store(true, Relaxed);
     }
 
-    Ok(())
+    Ok(failed)
 }
@@ -0,0 +1,204 @@
+use crate::{ cli::{ ARGS, ProxyStrategy }, http::{ self, CLIENT }, limiter };
+use anyhow::{ Context, Result, bail };
+use reqwest::{ Client, RequestBuilder, Response, StatusCode, Url };
+use std::{
+    fs,
+    process::exit,
+    sync::{ LazyLock, atomic::{ AtomicBool, AtomicU64, AtomicUsize, Ordering::{ Relaxed, SeqCst } } },
+};
+
+/// Consecutive connection errors or 403s after which a proxy is no longer used
+const EJECT_AFTER: usize = 5;
+
+static POOL: LazyLock<Vec<Proxy>> = LazyLock::new(||
+    ARGS.proxy_file
+        .as_deref()
+        .map(|path|
+            read(path).unwrap_or_else(|err| {
+                eprintln!("{err:#}");
+                exit(1);
+            })
+        )
+        .unwrap_or_default()
+);
+
+static NEXT: AtomicUsize = AtomicUsize::new(0);
+
+/// Number of ejected proxies, reserved before a proxy is marked as ejected so that
+/// concurrent failures can never eject the whole pool
+static EJECTED: AtomicUsize = AtomicUsize::new(0);
+
+struct Proxy {
+    url: String,
+    client: Client,
+    active: AtomicUsize,
+    successes: AtomicU64,
+    failures: AtomicU64,
+    strikes: AtomicUsize,
+    ejected: AtomicBool,
+}
+
+impl Proxy {
+    fn succeed(&self) {
+        self.successes.fetch_add(1, Relaxed);
+        self.strikes.store(0, Relaxed);
+    }
+
+    fn fail(&self) {
+        self.failures.fetch_add(1, Relaxed);
+
+        if self.strikes.fetch_add(1, Relaxed) + 1 < EJECT_AFTER || self.is_ejected() {
+            return;
+        }
+
+        // the last usable proxy is kept, failing downloads are retried anyway
+        let reserved = EJECTED.fetch_update(SeqCst, SeqCst, |ejected| {
+            (ejected + 1 < POOL.len()).then_some(ejected + 1)
+        });
+
+        // another task may have ejected this proxy in the meantime
+        if reserved.is_ok() && self.ejected.swap(true, SeqCst) {
+            EJECTED.fetch_sub(1, SeqCst);
+        }
+    }
+
+    fn is_ejected(&self) -> bool {
+        self.ejected.load(SeqCst)
+    }
+
+    /// Proxy URL without credentials
+    fn to_redacted(&self) -> String {
+        match Url::parse(&self.url) {
+            Ok(mut url) if url.password().is_some() => {
+                let _ = url.set_password(Some("***"));
+                url.to_string()
+            }
+            _ => self.url.clone(),
+        }
+    }
+}
+
+/// Reads proxy URLs, one per line, ignoring blank lines and comments (`#`)
+fn read(path: &str) -> Result<Vec<Proxy>> {
+    let text = fs::read_to_string(path).with_context(|| format!("read proxy file: {path}"))?;
+
+    let mut proxies = Vec::new();
+
+    for line in text.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        proxies.push(Proxy {
+            url: line.to_string(),
+            client: http::build_client(Some(line))?,
+            active: AtomicUsize::new(0),
+            successes: AtomicU64::new(0),
+            failures: AtomicU64::new(0),
+            strikes: AtomicUsize::new(0),
+            ejected: AtomicBool::new(false),
+        });
+    }
+
+    if proxies.is_empty() {
+        bail!("no proxies found in {path}");
+    }
+
+    Ok(proxies)
+}
+
+/// Reads `--proxy-file` up front, so that errors are reported before indexing starts
+pub fn init() {
+    LazyLock::force(&POOL);
+}
+
+/// A proxy that was not ejected, `None` only without `--proxy-file`
+fn pick() -> Option<&'static Proxy> {
+    let proxies: Vec<&Proxy> = POOL.iter().filter(|proxy| !proxy.is_ejected()).collect();
+
+    let proxy = match ARGS.proxy_strategy {
+        ProxyStrategy::RoundRobin => {
+            let next = NEXT.fetch_add(1, Relaxed);
+            *proxies.get(next % proxies.len().max(1))?
+        }
+        ProxyStrategy::LeastLoaded =>
+            proxies.into_iter().min_by_key(|proxy| proxy.active.load(Relaxed))?,
+    };
+
+    proxy.active.fetch_add(1, Relaxed);
+
+    Some(proxy)
+}
+
+/// Proxy from `--proxy-file` assigned to a download or an API request, released when dropped.
+///
+/// Without a proxy file, requests are sent by the global client.
+pub struct Lease(Option<&'static Proxy>);
+
+impl Lease {
+    pub fn new() -> Self {
+        Self(pick())
+    }
+
+    /// Sends a request through the assigned proxy, switching to another one if it was
+    /// ejected in the meantime, and records the outcome
+    pub async fn send(
+        &mut self,
+        request: impl FnOnce(&Client) -> RequestBuilder
+    ) -> reqwest::Result<Response> {
+        if self.0.is_some_and(Proxy::is_ejected) {
+            *self = Self::new();
+        }
+
+        let client = match self.0 {
+            Some(proxy) => &proxy.client,
+            None if POOL.is_empty() => &*CLIENT,
+            // never fall back to a direct connection when proxies were asked for
+            None => {
+                eprintln!("proxy: no usable proxy left in {}", ARGS.proxy_file.as_deref().unwrap_or_default());
+                exit(1);
+            }
+        };
+
+        let result = limiter::send(request(client)).await;
+
+        if let Some(proxy) = self.0 {
+            match &result {
+                Ok(response) if response.status() == StatusCode::FORBIDDEN => proxy.fail(),
+                Ok(_) => proxy.succeed(),
+                Err(err) if err.is_connect() || err.is_timeout() => proxy.fail(),
+                Err(_) => {}
+            }
+        }
+
+        result
+    }
+}
+
+impl Drop for Lease {
+    fn drop(&mut self) {
+        if let Some(proxy) = self.0 {
+            proxy.active.fetch_sub(1, Relaxed);
+        }
+    }
+}
+
+/// Prints the success rate of every proxy that was used
+#[allow(clippy::cast_precision_loss)]
+pub fn print_stats() {
+    for proxy in POOL.iter() {
+        let successes = proxy.successes.load(Relaxed);
+        let total = successes + proxy.failures.load(Relaxed);
+
+        if total == 0 {
+            continue;
+        }
+
+        eprintln!(
+            "proxy {}: {successes}/{total} requests succeeded ({:.1}%){}",
+            proxy.to_redacted(),
+            (successes as f64 / total as f64) * 100.0,
+            if proxy.is_ejected() { ", ejected" } else { "" }
+        );
+    }
+}
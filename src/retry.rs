@@ -0,0 +1,178 @@
+use crate::cli::ARGS;
+use reqwest::{ StatusCode, header::{ HeaderMap, RETRY_AFTER } };
+use std::time::{ Duration, SystemTime, UNIX_EPOCH };
+
+/// Upper bound for a single delay, including those requested via `Retry-After`
+const MAX_DELAY: Duration = Duration::from_secs(300);
+
+const MONTHS: [&str; 12] = [
+    "Jan",
+    "Feb",
+    "Mar",
+    "Apr",
+    "May",
+    "Jun",
+    "Jul",
+    "Aug",
+    "Sep",
+    "Oct",
+    "Nov",
+    "Dec",
+];
+
+/// Interrupted downloads that made progress are resumed right away, up to this many times
+pub const MAX_RESUMES: usize = 100;
+
+/// Failed attempts of a download; the size check (HEAD) and the download (GET) each get
+/// `--max-retries`
+#[derive(Debug, Default)]
+pub struct Retries {
+    pub head: usize,
+    pub get: usize,
+    /// Interrupted attempts that made progress, these are not retries
+    pub resumed: usize,
+}
+
+impl Retries {
+    pub fn total(&self) -> usize {
+        self.head + self.get
+    }
+}
+
+/// Why a request is retried, which determines the base delay
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Cause {
+    /// Connection errors, timeouts and unexpected responses
+    Error,
+    /// 403 and 429
+    RateLimit,
+    /// 5xx
+    ServerError,
+}
+
+impl Cause {
+    /// Statuses worth waiting for, `None` for everything else
+    pub fn from_status(status: StatusCode) -> Option<Self> {
+        if status == StatusCode::FORBIDDEN || status == StatusCode::TOO_MANY_REQUESTS {
+            Some(Cause::RateLimit)
+        } else if status.is_server_error() {
+            Some(Cause::ServerError)
+        } else {
+            None
+        }
+    }
+
+    fn base(self) -> Duration {
+        match self {
+            Cause::Error => ARGS.retry_delay,
+            Cause::RateLimit => ARGS.rate_limit_backoff,
+            Cause::ServerError => ARGS.server_error_delay,
+        }
+    }
+}
+
+/// Delay before retry number `retries` (starting at 0).
+///
+/// The server's `Retry-After` takes precedence, otherwise the base delay doubles with
+/// every retry. At least half of that delay is always waited, the rest is random, so
+/// that concurrent tasks do not retry all at once.
+pub fn delay(cause: Cause, retries: usize, retry_after: Option<Duration>) -> Duration {
+    if let Some(retry_after) = retry_after {
+        return retry_after.min(MAX_DELAY);
+    }
+
+    let delay = cause
+        .base()
+        .saturating_mul(1 << retries.min(16))
+        .min(MAX_DELAY);
+
+    delay / 2 + delay.mul_f64(rand::random::<f64>() / 2.0)
+}
+
+/// Delay requested by the server, given in seconds or as an HTTP date
+pub fn retry_after(headers: &HeaderMap) -> Option<Duration> {
+    let value = headers.get(RETRY_AFTER)?.to_str().ok()?.trim();
+
+    if let Ok(secs) = value.parse::<u64>() {
+        return Some(Duration::from_secs(secs));
+    }
+
+    let date = UNIX_EPOCH + Duration::from_secs(parse_http_date(value)?);
+
+    Some(date.duration_since(SystemTime::now()).unwrap_or_default())
+}
+
+/// Parses an IMF-fixdate (`Sun, 06 Nov 1994 08:49:37 GMT`) into seconds since the epoch
+fn parse_http_date(date: &str) -> Option<u64> {
+    let mut parts = date.split_whitespace().skip(1);
+
+    let day: i64 = parts.next()?.parse().ok()?;
+    let month = parts.next()?;
+    let month = (MONTHS.iter().position(|m| *m == month)? as i64) + 1;
+    let year: i64 = parts.next()?.parse().ok()?;
+
+    let mut time = parts.next()?.split(':').map(|n| n.parse::<i64>().ok());
+    let (hours, minutes, seconds) = (time.next()??, time.next()??, time.next()??);
+
+    // days since the epoch in the proleptic Gregorian calendar
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = era * 146_097 + day_of_era - 719_468;
+
+    u64::try_from(days * 86_400 + hours * 3600 + minutes * 60 + seconds).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use reqwest::header::HeaderValue;
+
+    fn headers(retry_after: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(RETRY_AFTER, HeaderValue::from_str(retry_after).unwrap());
+        headers
+    }
+
+    #[test]
+    fn parses_http_dates() {
+        assert_eq!(parse_http_date("Thu, 01 Jan 1970 00:00:00 GMT"), Some(0));
+        assert_eq!(parse_http_date("Sun, 06 Nov 1994 08:49:37 GMT"), Some(784_111_777));
+        assert_eq!(parse_http_date("Tue, 29 Feb 2000 12:00:00 GMT"), Some(951_825_600));
+        assert_eq!(parse_http_date("Wed, 31 Dec 2025 23:59:59 GMT"), Some(1_767_225_599));
+        assert_eq!(parse_http_date("Mon, 01 Mar 2100 00:00:00 GMT"), Some(4_107_542_400));
+    }
+
+    #[test]
+    fn rejects_invalid_http_dates() {
+        assert_eq!(parse_http_date("tomorrow"), None);
+        assert_eq!(parse_http_date("Sun, 06 Foo 1994 08:49:37 GMT"), None);
+        assert_eq!(parse_http_date("Sun, 06 Nov 1994 08:49 GMT"), None);
+        assert_eq!(parse_http_date("Wed, 31 Dec 1969 23:59:59 GMT"), None);
+    }
+
+    #[test]
+    fn reads_retry_after() {
+        assert_eq!(retry_after(&HeaderMap::new()), None);
+        assert_eq!(retry_after(&headers("120")), Some(Duration::from_secs(120)));
+        assert_eq!(retry_after(&headers(" 7 ")), Some(Duration::from_secs(7)));
+        assert_eq!(retry_after(&headers("soon")), None);
+
+        // dates in the past mean no delay
+        assert_eq!(retry_after(&headers("Sun, 06 Nov 1994 08:49:37 GMT")), Some(Duration::ZERO));
+
+        let future = jiff::Timestamp::now() + jiff::SignedDuration::from_secs(100);
+        let future = future.strftime("%a, %d %b %Y %H:%M:%S GMT").to_string();
+
+        let delay = retry_after(&headers(&future)).unwrap();
+        assert!(delay > Duration::from_secs(90) && delay <= Duration::from_secs(100));
+    }
+
+    #[test]
+    fn caps_retry_after() {
+        assert_eq!(delay(Cause::RateLimit, 0, Some(Duration::from_secs(30))), Duration::from_secs(30));
+        assert_eq!(delay(Cause::RateLimit, 0, Some(Duration::from_secs(3600))), MAX_DELAY);
+    }
+}
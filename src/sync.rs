@@ -0,0 +1,128 @@
+use crate::{ cli::{ ARGS, SyncArgs }, pretty, target::{ self, Target } };
+use anyhow::{ Context, Result };
+use std::{ fs, io::ErrorKind, path::PathBuf, process::exit };
+
+/// Watchlist of target URLs, in the same format as `--input-file`
+pub fn watchlist_pathbuf() -> PathBuf {
+    PathBuf::from_iter([&ARGS.output_path, "db", "watchlist.txt"])
+}
+
+fn normalize(url: &str) -> &str {
+    url.strip_suffix('/').unwrap_or(url)
+}
+
+fn read_watchlist() -> Result<Vec<String>> {
+    match fs::read_to_string(watchlist_pathbuf()) {
+        Ok(text) => Ok(text.lines().map(ToString::to_string).collect()),
+        Err(err) if err.kind() == ErrorKind::NotFound => Ok(Vec::new()),
+        Err(err) => Err(err).context("read watchlist"),
+    }
+}
+
+fn write_watchlist(lines: &[String]) -> Result<()> {
+    fs::create_dir_all(PathBuf::from_iter([&ARGS.output_path, "db"]))?;
+
+    let mut text = lines.join("\n");
+    text.push('\n');
+
+    fs::write(watchlist_pathbuf(), text).context("write watchlist")
+}
+
+/// Adds, removes or lists watchlist entries; returns whether anything was requested
+pub fn manage(args: &SyncArgs) -> Result<bool> {
+    if args.add.is_empty() && args.remove.is_empty() && !args.list {
+        return Ok(false);
+    }
+
+    let mut lines = read_watchlist()?;
+
+    let url_of = |line: &str| line.split_whitespace().next().map(normalize).map(str::to_string);
+
+    let before = lines.len();
+
+    lines.retain(|line| {
+        url_of(line).is_none_or(|url| !args.remove.iter().any(|r| normalize(r) == url))
+    });
+
+    let removed = before - lines.len();
+
+    let mut added = 0;
+
+    for url in &args.add {
+        if !lines.iter().any(|line| url_of(line).as_deref() == Some(normalize(url))) {
+            lines.push(normalize(url).to_string());
+            added += 1;
+        }
+    }
+
+    if added > 0 || removed > 0 {
+        write_watchlist(&lines)?;
+        eprintln!(
+            "watchlist: added {}, removed {}",
+            pretty::anything(added, "URL", "URLs"),
+            pretty::anything(removed, "URL", "URLs")
+        );
+    }
+
+    if args.list {
+        for line in &lines {
+            println!("{line}");
+        }
+    }
+
+    Ok(true)
+}
+
+/// Resolves the watchlist, leaving out creators that were not updated since the last sync.
+///
+/// Targets are returned along with their update date, which is saved once they were
+/// downloaded successfully.
+pub async fn targets() -> Vec<(Target, Option<String>)> {
+    let path = watchlist_pathbuf();
+
+    if !path.exists() {
+        eprintln!("The watchlist is empty, use `kumono sync --add <URL>` to add targets.");
+        exit(1);
+    }
+
+    let inputs = target::read_input(&path.to_string_lossy()).unwrap_or_else(|err| {
+        eprintln!("{err:#}");
+        exit(1);
+    });
+
+    let mut targets = Vec::new();
+
+    for target in Target::from_inputs(inputs).await {
+        let updated = match target.updated().await {
+            Ok(updated) => updated,
+            Err(err) => {
+                eprintln!("sync: failed to check {target} for updates: {err}");
+                None
+            }
+        };
+
+        if let Some(updated) = &updated && read_updated(&target).as_ref() == Some(updated) {
+            eprintln!("sync: {target} is up to date");
+            continue;
+        }
+
+        targets.push((target, updated));
+    }
+
+    if targets.is_empty() {
+        eprintln!("sync: nothing to do");
+        exit(0);
+    }
+
+    targets
+}
+
+fn read_updated(target: &Target) -> Option<String> {
+    fs::read_to_string(target.to_updated_pathbuf()).ok().map(|updated| updated.trim().to_string())
+}
+
+/// Saves the update date of a target after it was downloaded
+pub fn write_updated(target: &Target, updated: &str) -> Result<()> {
+    let path = target.to_updated_pathbuf();
+    fs::write(&path, updated).with_context(|| format!("write update date: {}", path.display()))
+}
@@ -1,10 +1,18 @@
-use crate::{ cli::ARGS, http::CLIENT };
+use crate::{ api, cli::{ ARGS, Extra }, ext::ExtFilter, favorites, mirror };
 use anyhow::{ bail, Context, Result };
-use itertools::Itertools;
+use clap::ValueEnum;
 use regex::{ Captures, Regex };
 use serde::Deserialize;
 use strum_macros::{ Display, EnumString };
-use std::{ fmt, fs::File, io::Read, path::PathBuf, process::exit, sync::LazyLock };
+use std::{
+    collections::HashMap,
+    fmt,
+    fs::{ self, File },
+    io::{ self, Read },
+    path::PathBuf,
+    process::exit,
+    sync::LazyLock,
+};
 
 #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub enum Target {
@@ -13,11 +21,13 @@ pub enum Target {
         user: String,
         subtype: SubType,
         archive: Vec<String>,
+        exts: Option<ExtFilter>,
     },
     Discord {
         server: String,
         channel: Option<String>,
         archive: Vec<String>,
+        exts: Option<ExtFilter>,
     },
 }
 
@@ -27,6 +37,7 @@ impl fmt::Display for Target {
             Target::Creator { service, user, subtype, .. } =>
                 format!("{service}/{user}{}", match subtype {
                     SubType::Post(p) => format!("/{p}"),
+                    SubType::Section(section) => format!("/{section}"),
                     _ => String::new(),
                 }),
             Target::Discord { server, channel, .. } =>
@@ -42,6 +53,8 @@ impl fmt::Display for Target {
 pub enum SubType {
     PageOffset(usize),
     Post(String),
+    /// DMs, fancards, etc. without the regular posts
+    Section(Extra),
     None,
 }
 
@@ -56,54 +69,137 @@ struct Info {
     public_id: Option<String>, // "theobrobine",
     relation_id: Option<u64>, // 8,
     has_chats: Option<bool>, // false
+    post_count: Option<usize>, // 1234
 }
 
 type LazyRegex = LazyLock<Regex>;
 
+/// Domains of both sites in target URLs
+static RE_SITES: LazyLock<String> = LazyLock::new(||
+    format!("{}|{}", Site::Coomer.pattern(), Site::Kemono.pattern())
+);
+
 static RE_LINKED: LazyRegex = LazyLock::new(|| {
     Regex::new(
-        r"^(?:https://)?(?:coomer\.(?:su|st|party)|kemono\.(?:su|cr|party))/(?<service>[a-z]+)/user/(?<user>[a-z|A-Z|0-9|\-|_|\.]+)/links$"
+        &format!(
+            r"^(?:https?://)?(?:{})/(?<service>[a-z]+)/user/(?<user>[a-z|A-Z|0-9|\-|_|\.]+)/links$",
+            *RE_SITES
+        )
     ).unwrap()
 });
 
 static RE_CREATOR: LazyRegex = LazyLock::new(|| {
     Regex::new(
-        r"^(?:https://)?(?:coomer\.(?:su|st|party)|kemono\.(?:su|cr|party))/(?<service>[a-z]+)/user/(?<user>[a-z|A-Z|0-9|\-|_|\.]+)$"
+        &format!(
+            r"^(?:https?://)?(?:{})/(?<service>[a-z]+)/user/(?<user>[a-z|A-Z|0-9|\-|_|\.]+)$",
+            *RE_SITES
+        )
     ).unwrap()
 });
 
 static RE_PAGE: LazyRegex = LazyLock::new(|| {
     Regex::new(
-        r"^(?:https://)?(?:coomer\.(?:su|st|party)|kemono\.(?:su|cr|party))/(?<service>[a-z]+)/user/(?<user>[a-z|A-Z|0-9|\-|_|\.]+)\?o=(?<offset>(0|50|[1-9]+(0|5)0))$"
+        &format!(
+            r"^(?:https?://)?(?:{})/(?<service>[a-z]+)/user/(?<user>[a-z|A-Z|0-9|\-|_|\.]+)\?o=(?<offset>(0|50|[1-9]+(0|5)0))$",
+            *RE_SITES
+        )
     ).unwrap()
 });
 
 static RE_POST: LazyRegex = LazyLock::new(|| {
     Regex::new(
-        r"^(?:https://)?(?:coomer\.(?:su|st|party)|kemono\.(?:su|cr|party))/(?<service>[a-z]+)/user/(?<user>[a-z|A-Z|0-9|\-|_|\.]+)/post/(?<post>[a-z|A-Z|0-9|\-|_|\.]+)$"
+        &format!(
+            r"^(?:https?://)?(?:{})/(?<service>[a-z]+)/user/(?<user>[a-z|A-Z|0-9|\-|_|\.]+)/post/(?<post>[a-z|A-Z|0-9|\-|_|\.]+)$",
+            *RE_SITES
+        )
+    ).unwrap()
+});
+
+static RE_SECTION: LazyRegex = LazyLock::new(|| {
+    Regex::new(
+        &format!(
+            r"^(?:https?://)?(?:{})/(?<service>[a-z]+)/user/(?<user>[a-z|A-Z|0-9|\-|_|\.]+)/(?<section>dms|fancards|announcements|community)$",
+            *RE_SITES
+        )
     ).unwrap()
 });
 
 static RE_DISCORD: LazyRegex = LazyLock::new(|| {
     Regex::new(
-        r"^(?:https://)?kemono\.(?:su|cr|party)/discord/server/(?<server>[0-9]{17,19})(/(?<channel>[0-9]{17,19}))?$"
+        &format!(
+            r"^(?:https?://)?(?:{})/discord/server/(?<server>[0-9]{{17,19}})(/(?<channel>[0-9]{{17,19}}))?$",
+            Site::Kemono.pattern()
+        )
     ).unwrap()
 });
 
-async fn linked_accounts(service: &Service, user: &str) -> Result<Vec<Info>> {
-    let mut accounts = Vec::new();
+async fn profile(service: &Service, user: &str) -> Result<Info> {
+    let path = format!("/{service}/user/{user}/profile");
+    Ok(api::get(service.to_site(), &path).await?.json().await?)
+}
 
-    let url = format!("https://{}/api/v1/{service}/user/{user}/profile", service.site());
-    let account: Info = CLIENT.get(url).send().await?.json().await?;
-    accounts.push(account);
+async fn linked_accounts(service: &Service, user: &str) -> Result<Vec<Info>> {
+    let mut accounts = vec![profile(service, user).await?];
 
-    let linked_url = format!("https://{}/api/v1/{service}/user/{user}/links", service.site());
-    let mut linked_accounts: Vec<Info> = CLIENT.get(linked_url).send().await?.json().await?;
+    let linked_path = format!("/{service}/user/{user}/links");
+    let mut linked_accounts: Vec<Info> = api
+        ::get(service.to_site(), &linked_path).await?
+        .json().await?;
     accounts.append(&mut linked_accounts);
 
     Ok(accounts)
 }
 
+/// Reads target URLs from a file (or stdin for `-`), one per line.
+///
+/// Blank lines and comments (`#`) are ignored. A URL may be followed by
+/// `include=ext,...` or `exclude=ext,...` to override the extension filter.
+pub fn read_input(path: &str) -> Result<Vec<(String, Option<ExtFilter>)>> {
+    let text = if path == "-" {
+        let mut buf = String::new();
+        io::stdin().read_to_string(&mut buf).context("read target URLs from stdin")?;
+        buf
+    } else {
+        fs::read_to_string(path).with_context(|| format!("read input file: {path}"))?
+    };
+
+    parse_input(&text, path)
+}
+
+/// Parses the lines of an input file: a URL, optionally followed by `include=` or
+/// `exclude=` with a comma separated list of extensions, and a `#` comment
+fn parse_input(text: &str, path: &str) -> Result<Vec<(String, Option<ExtFilter>)>> {
+    let mut inputs = Vec::new();
+
+    for (i, line) in text.lines().enumerate() {
+        let mut tokens = line.split_whitespace().take_while(|token| !token.starts_with('#'));
+
+        let Some(url) = tokens.next() else {
+            continue;
+        };
+
+        let mut exts = None;
+
+        for token in tokens {
+            let filter = match token.split_once('=') {
+                Some(("include", list)) => ExtFilter::Include(ExtFilter::parse_list(list)),
+                Some(("exclude", list)) => ExtFilter::Exclude(ExtFilter::parse_list(list)),
+                _ => bail!("{path}:{}: invalid option: {token}", i + 1),
+            };
+
+            if exts.is_some() {
+                bail!("{path}:{}: only one of include= or exclude= is allowed per line", i + 1);
+            }
+
+            exts = Some(filter);
+        }
+
+        inputs.push((url.to_string(), exts));
+    }
+
+    Ok(inputs)
+}
+
 impl Target {
     pub fn as_service(&self) -> Service {
         match self {
@@ -113,21 +209,84 @@ impl Target {
     }
 
     pub async fn from_args() -> Vec<Target> {
-        let mut targets = Vec::new();
+        let mut inputs: Vec<(String, Option<ExtFilter>)> = ARGS.urls
+            .iter()
+            .map(|url| (url.clone(), None))
+            .collect();
+
+        if let Some(path) = &ARGS.input_file {
+            match read_input(path) {
+                Ok(mut lines) => inputs.append(&mut lines),
+                Err(err) => {
+                    eprintln!("{err:#}");
+                    exit(1);
+                }
+            }
+        }
 
-        for url in &ARGS.urls {
-            match Target::from_url(url.strip_suffix('/').unwrap_or(url)).await {
-                Ok(mut target) => targets.append(&mut target),
-                Err(err) => eprintln!("{err}"),
+        match favorites::inputs().await {
+            Ok(mut favorites) => inputs.append(&mut favorites),
+            Err(err) => {
+                eprintln!("{err:#}");
+                exit(1);
             }
         }
 
+        let targets = Target::from_inputs(inputs).await;
+
         if targets.is_empty() {
             eprintln!("No valid target URLs were provided.");
             exit(1);
         }
 
-        targets.into_iter().unique().collect()
+        targets
+    }
+
+    /// Resolves URLs with optional extension filter overrides
+    pub async fn from_inputs(inputs: Vec<(String, Option<ExtFilter>)>) -> Vec<Target> {
+        let mut targets = Vec::new();
+
+        for (url, exts) in inputs {
+            match Target::from_url(url.strip_suffix('/').unwrap_or(&url)).await {
+                Ok(found) => {
+                    for mut target in found {
+                        target.set_exts(exts.clone());
+                        targets.push(target);
+                    }
+                }
+                Err(err) => eprintln!("{err}"),
+            }
+        }
+
+        Self::dedupe(targets)
+    }
+
+    /// Removes targets that were given more than once, combining their extension
+    /// filters so that files wanted by any of them are downloaded
+    fn dedupe(targets: Vec<Target>) -> Vec<Target> {
+        let mut unique: Vec<Target> = Vec::new();
+        let mut seen: HashMap<Target, usize> = HashMap::new();
+
+        for target in targets {
+            let mut identity = target.clone();
+            identity.set_exts(None);
+
+            if let Some(&i) = seen.get(&identity) {
+                let existing = &mut unique[i];
+
+                if existing.exts() != target.exts() {
+                    eprintln!("{target}: listed more than once, combining its extension filters");
+                    existing.set_exts(ExtFilter::union(existing.exts(), target.exts()));
+                }
+
+                continue;
+            }
+
+            seen.insert(identity, unique.len());
+            unique.push(target);
+        }
+
+        unique
     }
 
     async fn from_url(url: &str) -> Result<Vec<Self>> {
@@ -149,13 +308,19 @@ impl Target {
 
             for info in linked {
                 let mut target = if info.service == "discord" {
-                    Target::Discord { server: info.id, channel: None, archive: Vec::new() }
+                    Target::Discord {
+                        server: info.id,
+                        channel: None,
+                        archive: Vec::new(),
+                        exts: None,
+                    }
                 } else {
                     Target::Creator {
                         service: info.service.parse()?,
                         user: info.id,
                         subtype: SubType::None,
                         archive: Vec::new(),
+                        exts: None,
                     }
                 };
 
@@ -178,6 +343,7 @@ impl Target {
                 user: extract_unwrap(&caps, "user"),
                 subtype: SubType::None,
                 archive,
+                exts: None,
             }
         } else if RE_PAGE.is_match(url) {
             let caps = capture(&RE_PAGE);
@@ -186,6 +352,7 @@ impl Target {
                 user: extract_unwrap(&caps, "user"),
                 subtype: SubType::PageOffset(extract_unwrap(&caps, "offset").parse()?),
                 archive,
+                exts: None,
             }
         } else if RE_POST.is_match(url) {
             let caps = capture(&RE_POST);
@@ -194,6 +361,20 @@ impl Target {
                 user: extract_unwrap(&caps, "user"),
                 subtype: SubType::Post(extract_unwrap(&caps, "post")),
                 archive,
+                exts: None,
+            }
+        } else if RE_SECTION.is_match(url) {
+            let caps = capture(&RE_SECTION);
+            Target::Creator {
+                service: extract_unwrap(&caps, "service").parse()?,
+                user: extract_unwrap(&caps, "user"),
+                subtype: SubType::Section(
+                    Extra::from_str(&extract_unwrap(&caps, "section"), false).map_err(
+                        anyhow::Error::msg
+                    )?
+                ),
+                archive,
+                exts: None,
             }
         } else if RE_DISCORD.is_match(url) {
             let caps = capture(&RE_DISCORD);
@@ -201,6 +382,7 @@ impl Target {
                 server: extract_unwrap(&caps, "server"),
                 channel: extract(&caps, "channel"),
                 archive,
+                exts: None,
             }
         } else {
             bail!("Invalid URL: {url}");
@@ -213,6 +395,14 @@ impl Target {
         Ok(vec![target])
     }
 
+    /// Whether the target covers all posts of a creator or server
+    pub fn is_whole(&self) -> bool {
+        matches!(
+            self,
+            Target::Creator { subtype: SubType::None, .. } | Target::Discord { channel: None, .. }
+        )
+    }
+
     fn user(&self) -> String {
         match self {
             Target::Creator { user, .. } => user.to_string(),
@@ -221,10 +411,15 @@ impl Target {
     }
 
     pub fn read_archive(&mut self) -> Result<()> {
+        // a dry run must not create the archive file
+        if ARGS.dry_run && !self.to_archive_pathbuf().exists() {
+            return Ok(());
+        }
+
         let mut archive = File::options()
             .read(true)
-            .append(true)
-            .create(true)
+            .append(!ARGS.dry_run)
+            .create(!ARGS.dry_run)
             .truncate(false)
             .open(self.to_archive_pathbuf())
             .with_context(|| format!("Failed to open archive file for {self}"))?;
@@ -247,6 +442,22 @@ impl Target {
         }
     }
 
+    fn set_exts(&mut self, filter: Option<ExtFilter>) {
+        match self {
+            Target::Creator { exts, .. } | Target::Discord { exts, .. } => {
+                *exts = filter;
+            }
+        }
+    }
+
+    /// Extension filter for this target, falling back to `--include`/`--exclude`
+    pub fn exts(&self) -> Option<ExtFilter> {
+        match self {
+            Target::Creator { exts, .. } | Target::Discord { exts, .. } =>
+                exts.clone().or_else(ExtFilter::from_args),
+        }
+    }
+
     pub fn archive(&self) -> &Vec<String> {
         match &self {
             Target::Creator { archive, .. } | Target::Discord { archive, .. } => archive,
@@ -261,6 +472,40 @@ impl Target {
         ])
     }
 
+    /// Date of the creator's last update on the site, for `sync`
+    pub async fn updated(&self) -> Result<Option<String>> {
+        match self {
+            Target::Creator { service, user, subtype: SubType::None, .. } =>
+                Ok(Some(profile(service, user).await?.updated)),
+            _ => Ok(None),
+        }
+    }
+
+    /// Number of posts according to the creator's profile, for retrieving pages concurrently
+    pub async fn post_count(&self) -> Result<Option<usize>> {
+        match self {
+            Target::Creator { service, user, subtype: SubType::None, .. } =>
+                Ok(profile(service, user).await?.post_count),
+            _ => Ok(None),
+        }
+    }
+
+    pub fn to_updated_pathbuf(&self) -> PathBuf {
+        PathBuf::from_iter([
+            &ARGS.output_path,
+            "db",
+            &format!("{}+{}.updated", self.as_service(), self.user()),
+        ])
+    }
+
+    pub fn to_last_run_pathbuf(&self) -> PathBuf {
+        PathBuf::from_iter([
+            &ARGS.output_path,
+            "db",
+            &format!("{}+{}.last-run", self.as_service(), self.user()),
+        ])
+    }
+
     pub fn to_pathbuf(&self, file: Option<&str>) -> PathBuf {
         PathBuf::from_iter([
             &ARGS.output_path,
@@ -289,12 +534,154 @@ pub enum Service {
 }
 
 impl Service {
-    pub fn site(self) -> &'static str {
+    pub fn to_site(self) -> Site {
         #[allow(clippy::enum_glob_use)]
         use Service::*;
         match self {
-            CandFans | Fansly | OnlyFans => "coomer.st",
-            _ => "kemono.cr",
+            CandFans | Fansly | OnlyFans => Site::Coomer,
+            _ => Site::Kemono,
+        }
+    }
+
+    pub fn base_url(self) -> String {
+        self.to_site().base_url()
+    }
+}
+
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Hash,
+    Deserialize,
+    EnumString,
+    Display,
+    ValueEnum
+)]
+#[strum(ascii_case_insensitive, serialize_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum Site {
+    Coomer,
+    Kemono,
+}
+
+impl Site {
+    /// Configured domains in order of preference, optionally with a scheme
+    fn domains(self) -> &'static [String] {
+        match self {
+            Site::Coomer => &ARGS.coomer_host,
+            Site::Kemono => &ARGS.kemono_host,
         }
     }
+
+    /// Domain currently in use, see `mirror`
+    fn configured(self) -> &'static str {
+        let domains = self.domains();
+        domains[mirror::current(self) % domains.len()].trim_end_matches('/')
+    }
+
+    /// Number of configured domains
+    pub fn mirrors(self) -> usize {
+        self.domains().len()
+    }
+
+    /// Domain (and port) without a scheme
+    pub fn host(self) -> &'static str {
+        strip_scheme(self.configured())
+    }
+
+    /// `https://{host}`, unless another scheme was configured
+    pub fn base_url(self) -> String {
+        to_base_url(self.configured())
+    }
+
+    /// Base URLs of all configured domains
+    pub fn base_urls(self) -> Vec<String> {
+        self.domains()
+            .iter()
+            .map(|domain| to_base_url(domain.trim_end_matches('/')))
+            .collect()
+    }
+
+    /// `--kemono-api`/`--coomer-api`, `--api-base`, or `{base_url}/api/v1`
+    pub fn api_url(self) -> String {
+        let api = match self {
+            Site::Coomer => &ARGS.coomer_api,
+            Site::Kemono => &ARGS.kemono_api,
+        };
+
+        api.as_ref().or(ARGS.api_base.as_ref()).map_or_else(
+            || format!("{}/api/v1", self.base_url()),
+            |base| base.trim_end_matches('/').to_string()
+        )
+    }
+
+    /// Matches the configured domains as well as the known ones in target URLs
+    fn pattern(self) -> String {
+        let known = match self {
+            Site::Coomer => r"coomer\.(?:su|st|party)",
+            Site::Kemono => r"kemono\.(?:su|cr|party)",
+        };
+
+        self.domains()
+            .iter()
+            .map(|domain| regex::escape(strip_scheme(domain.trim_end_matches('/'))))
+            .fold(known.to_string(), |pattern, domain| pattern + "|" + &domain)
+    }
+}
+
+fn strip_scheme(domain: &str) -> &str {
+    domain.split_once("://").map_or(domain, |(_, host)| host)
+}
+
+fn to_base_url(domain: &str) -> String {
+    if domain.contains("://") { domain.to_string() } else { format!("https://{domain}") }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn exts(list: &[&str]) -> Vec<String> {
+        list.iter().map(ToString::to_string).collect()
+    }
+
+    #[test]
+    fn parses_input_lines() {
+        let text = "\
+# targets
+https://kemono.cr/patreon/user/1
+https://kemono.cr/patreon/user/2 include=JPG,png  # images only
+
+https://coomer.st/onlyfans/user/a exclude=mp4
+";
+
+        assert_eq!(parse_input(text, "input.txt").unwrap(), [
+            ("https://kemono.cr/patreon/user/1".to_string(), None),
+            (
+                "https://kemono.cr/patreon/user/2".to_string(),
+                Some(ExtFilter::Include(exts(&["jpg", "png"]))),
+            ),
+            (
+                "https://coomer.st/onlyfans/user/a".to_string(),
+                Some(ExtFilter::Exclude(exts(&["mp4"]))),
+            ),
+        ]);
+    }
+
+    #[test]
+    fn rejects_invalid_input_options() {
+        let err = parse_input("url\nurl include=jpg exclude=mp4", "input.txt").unwrap_err();
+        assert_eq!(err.to_string(), "input.txt:2: only one of include= or exclude= is allowed per line");
+
+        let err = parse_input("url include=jpg include=png", "input.txt").unwrap_err();
+        assert_eq!(err.to_string(), "input.txt:1: only one of include= or exclude= is allowed per line");
+
+        let err = parse_input("url only=jpg", "input.txt").unwrap_err();
+        assert_eq!(err.to_string(), "input.txt:1: invalid option: only=jpg");
+    }
 }
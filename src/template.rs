@@ -0,0 +1,167 @@
+use crate::file::PostFile;
+use regex::{ Captures, Regex };
+use std::{ path::Path, sync::LazyLock };
+
+const PLACEHOLDERS: [&str; 7] = ["hash", "name", "ext", "post_id", "title", "published", "index"];
+
+/// Longest value (in characters) inserted for free-form placeholders like `{title}`
+const MAX_VALUE_LEN: usize = 100;
+
+static RE_PLACEHOLDER: LazyLock<Regex> = LazyLock::new(||
+    Regex::new(r"\{(?<key>[a-z_]+)\}").unwrap()
+);
+
+/// Validates a `--filename` template
+pub fn parse(template: &str) -> Result<String, String> {
+    if template.contains(['/', '\\']) {
+        return Err("template must not contain path separators".to_string());
+    }
+
+    for caps in RE_PLACEHOLDER.captures_iter(template) {
+        let key = &caps["key"];
+        if !PLACEHOLDERS.contains(&key) {
+            return Err(
+                format!("unknown placeholder {{{key}}}, expected one of: {}", PLACEHOLDERS.join(", "))
+            );
+        }
+    }
+
+    if !RE_PLACEHOLDER.is_match(template) {
+        return Err("template must contain at least one placeholder".to_string());
+    }
+
+    // otherwise different files may end up with the same name, and all but one are skipped
+    if !is_unique(template) {
+        return Err(
+            "template must contain {hash}, or both {post_id} and {index}, to keep file names unique".to_string()
+        );
+    }
+
+    Ok(template.to_string())
+}
+
+/// Whether the template yields a different name for every file of a target
+fn is_unique(template: &str) -> bool {
+    let keys: Vec<&str> = RE_PLACEHOLDER.captures_iter(template)
+        .map(|caps| caps.name("key").map_or("", |key| key.as_str()))
+        .collect();
+
+    keys.contains(&"hash") || (keys.contains(&"post_id") && keys.contains(&"index"))
+}
+
+/// Resolves the template for a single file, falling back to the CDN name
+pub fn render(template: &str, file: &PostFile) -> String {
+    let cdn_name = file.to_name();
+    let hash = Path::new(&cdn_name)
+        .file_stem()
+        .map_or_else(|| cdn_name.clone(), |s| s.to_string_lossy().to_string());
+
+    let post = file.post.as_deref();
+
+    let name = RE_PLACEHOLDER.replace_all(template, |caps: &Captures| {
+        match &caps["key"] {
+            "hash" => hash.clone(),
+            "name" =>
+                file.name
+                    .as_ref()
+                    .and_then(|n| Path::new(n).file_stem())
+                    .map_or_else(|| hash.clone(), |s| sanitize(&s.to_string_lossy())),
+            "ext" => file.to_extension().unwrap_or_default(),
+            "post_id" => post.map(|p| sanitize(&p.id)).unwrap_or_default(),
+            "title" =>
+                post
+                    .and_then(|p| p.title.as_deref())
+                    .map(sanitize)
+                    .unwrap_or_default(),
+            "published" =>
+                post
+                    .and_then(|p| p.published.as_deref())
+                    .and_then(|d| d.get(..10))
+                    .map(sanitize)
+                    .unwrap_or_default(),
+            "index" => file.index.to_string(),
+            _ => unreachable!("placeholders are validated by the argument parser"),
+        }
+    });
+
+    let name = name.trim().trim_end_matches('.');
+
+    if name.is_empty() { cdn_name } else { name.to_string() }
+}
+
+/// Makes a string safe to use as (part of) a file name
+pub fn sanitize(value: &str) -> String {
+    value
+        .trim()
+        .chars()
+        .map(|c| {
+            if c.is_control() || matches!(c, '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|') {
+                '_'
+            } else {
+                c
+            }
+        })
+        .take(MAX_VALUE_LEN)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::PostInfo;
+    use std::sync::Arc;
+
+    fn file(hash: &str, name: &str, index: usize) -> PostFile {
+        PostFile {
+            path: Some(format!("/{}/{}/{hash}.jpg", &hash[..2], &hash[2..4])),
+            name: Some(name.to_string()),
+            post: Some(
+                Arc::new(PostInfo {
+                    id: "123".to_string(),
+                    title: Some("a/b: c".to_string()),
+                    published: Some("2024-05-21T17:19:18".to_string()),
+                    ..PostInfo::default()
+                })
+            ),
+            index,
+        }
+    }
+
+    #[test]
+    fn parse_rejects_templates_without_unique_placeholders() {
+        assert!(parse("{name}.{ext}").is_err());
+        assert!(parse("{title}_{index}.{ext}").is_err());
+        assert!(parse("{post_id}.{ext}").is_err());
+
+        assert!(parse("{name}_{hash}.{ext}").is_ok());
+        assert!(parse("{published}_{post_id}_{index}_{name}.{ext}").is_ok());
+    }
+
+    #[test]
+    fn parse_rejects_invalid_templates() {
+        assert!(parse("{hash}/{name}").is_err());
+        assert!(parse("{hash}_{unknown}").is_err());
+        assert!(parse("static").is_err());
+    }
+
+    #[test]
+    fn render_fills_placeholders() {
+        let file = file(&"ab".repeat(32), "photo.png", 2);
+
+        assert_eq!(
+            render("{published}_{post_id}_{index}_{title}_{name}.{ext}", &file),
+            "2024-05-21_123_2_a_b_ c_photo.jpg"
+        );
+        assert_eq!(render("{hash}.{ext}", &file), format!("{}.jpg", "ab".repeat(32)));
+    }
+
+    #[test]
+    fn render_keeps_names_of_files_with_the_same_name_apart() {
+        let template = parse("{name}_{hash}.{ext}").unwrap();
+
+        let a = file(&"ab".repeat(32), "photo.jpg", 0);
+        let b = file(&"cd".repeat(32), "photo.jpg", 1);
+
+        assert_ne!(render(&template, &a), render(&template, &b));
+    }
+}